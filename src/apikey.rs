@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+use clap::Args;
+
+// Where a server's API key comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    Env(String),
+    File(PathBuf),
+    // First line of stdin, TTY or not
+    Stdin,
+    // Ask on the terminal, or read stdin when there isn't one
+    Prompt
+}
+
+// Flags for commands that take a key. None of them given means Prompt.
+#[derive(Debug, Clone, Default, Args)]
+#[group(multiple = false)]
+pub struct ApiKeyArgs {
+    // Read the API key from the first line of stdin
+    #[arg(long)]
+    pub apikey_stdin: bool,
+
+    // Read the API key from this file
+    #[arg(long)]
+    pub apikey_file: Option<PathBuf>,
+
+    // Read the API key from this environment variable
+    #[arg(long, value_name = "VAR")]
+    pub apikey_env: Option<String>
+}
+
+impl ApiKeyArgs {
+    // None when no flag was given
+    pub fn source(&self) -> Option<KeySource> {
+        match (self.apikey_stdin, &self.apikey_file, &self.apikey_env) {
+            (true, _, _) => Some(KeySource::Stdin),
+            (_, Some(path), _) => Some(KeySource::File(path.clone())),
+            (_, _, Some(var)) => Some(KeySource::Env(var.clone())),
+            _ => None
+        }
+    }
+}
+
+impl KeySource {
+    // env:NAME, file:PATH or prompt, as written in inventory files
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        match s.split_once(':') {
+            Some(("env", var)) if !var.is_empty() => Ok(KeySource::Env(var.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(KeySource::File(PathBuf::from(path))),
+            _ if s == "prompt" => Ok(KeySource::Prompt),
+            _ => Err("apikey must be env:NAME, file:PATH or prompt".to_string())
+        }
+    }
+
+    // The key, unless this is Prompt. That's left to read() so callers can
+    // check everything else before asking anyone anything.
+    pub fn resolve(&self) -> Result<Option<String>, String> {
+        let key = match self {
+            KeySource::Env(var) => std::env::var(var)
+                .map_err(|_| format!("environment variable {} is not set", var))?,
+            KeySource::File(path) => fs::read_to_string(path)
+                .map_err(|e| format!("unable to read {}: {}", path.display(), e))?,
+            KeySource::Stdin => read_line().map_err(|e| format!("unable to read stdin: {}", e))?,
+            KeySource::Prompt => return Ok(None)
+        };
+
+        match key.trim() {
+            "" => Err("the API key is empty".to_string()),
+            k => Ok(Some(k.to_string()))
+        }
+    }
+
+    pub fn read(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        match self.resolve()? {
+            Some(key) => Ok(key),
+            None => Ok(prompt_apikey(prompt)?.trim().to_string())
+        }
+    }
+}
+
+fn read_line() -> io::Result<String> {
+    let mut buf = String::new();
+    io::stdin().read_line(&mut buf)?;
+
+    Ok(buf)
+}
+
+// Prompt user for api key. When stdin isn't a terminal (piped in, or under
+// the test suite) take the first line of it instead.
+pub fn prompt_apikey(prompt: &str) -> Result<String, Box<dyn Error>> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?)
+    }
+
+    let buf = read_line()?;
+    if buf.trim().is_empty() {
+        return Err("No API key given on stdin".into())
+    }
+
+    Ok(buf)
+}
@@ -1,11 +1,21 @@
 use clap::Parser;
 
 use cpcm::command_domain::run_domain;
-use cpcm::command_server::run_server_add;
+use cpcm::command_server::{
+    run_server_add, run_server_edit, run_server_import, run_server_list, run_server_remove, run_server_test,
+    run_server_facts, run_server_rotate_token, run_server_tag_add, run_server_tag_list, run_server_tag_remove,
+    run_server_trust
+};
 use cpcm::command_init::initialize;
+use cpcm::command_history::run_changes;
+use cpcm::command_debug::run_debug_replay;
+use cpcm::command_secrets::{run_secrets_lock, run_secrets_migrate, run_secrets_rekey, run_secrets_unlock};
 
 use cpcm::cli::{
     Cpcm,
+    DebugSubcommand,
+    SecretsSubcommand,
+    ServerTagSubcommand,
     ServerSubcommand,
 };
 use cpcm::global_paths::GlobalPaths;
@@ -56,14 +66,35 @@ async fn main() {
         },
         Cpcm::Domain( domargs ) => run_domain(domargs, &paths, &config).await,
         Cpcm::Server(subcmd) => match subcmd {
-            ServerSubcommand::Add(s) => run_server_add(s, &paths, &config)
+            ServerSubcommand::Add(s) => run_server_add(s, &paths, &config).await,
+            ServerSubcommand::List(s) => run_server_list(s, &paths, &config),
+            ServerSubcommand::Test(s) => run_server_test(s, &paths, &config).await,
+            ServerSubcommand::Import(s) => run_server_import(s, &paths, &config),
+            ServerSubcommand::Edit(s) => run_server_edit(s, &paths, &config),
+            ServerSubcommand::Remove(s) => run_server_remove(s, &paths, &config),
+            ServerSubcommand::Trust(s) => run_server_trust(s, &paths, &config).await,
+            ServerSubcommand::RotateToken(s) => run_server_rotate_token(s, &paths, &config).await,
+            ServerSubcommand::Facts(s) => run_server_facts(s, &paths, &config).await,
+            ServerSubcommand::Tag(subcmd) => match subcmd {
+                ServerTagSubcommand::Add(t) => run_server_tag_add(t, &paths, &config),
+                ServerTagSubcommand::Remove(t) => run_server_tag_remove(t, &paths, &config),
+                ServerTagSubcommand::List(t) => run_server_tag_list(t, &paths, &config)
+            }
+        },
+        Cpcm::Changes(c) => run_changes(c, &paths, &config),
+        Cpcm::Debug(subcmd) => match subcmd {
+            DebugSubcommand::Replay(r) => run_debug_replay(r, &paths, &config)
+        },
+        Cpcm::Secrets(subcmd) => match subcmd {
+            SecretsSubcommand::Migrate => run_secrets_migrate(&paths, &config),
+            SecretsSubcommand::Rekey => run_secrets_rekey(&paths, &config),
+            SecretsSubcommand::Unlock(u) => run_secrets_unlock(u, &paths, &config),
+            SecretsSubcommand::Lock => run_secrets_lock(&paths, &config)
         },
     };
 
-    match r {
-        Err(e) => panic!("{:?}", e),
-        _ => {}
+    if let Err(e) = r {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
-
-    ()
 }
@@ -1,8 +1,18 @@
-use clap::Parser;
-use crate::command_domain::DomainArgs;
-use crate::command_server::ServerAdd;
+use std::time::Duration;
 
+use clap::{Parser, ValueEnum};
+use crate::command_debug::DebugReplay;
+use crate::command_secrets::SecretsUnlock;
+use crate::command_domain::{DomainArgs, DomainImport};
+use crate::command_history::{ChangesArgs, DomainHistory};
+use crate::command_server::{
+    ServerAdd, ServerEdit, ServerFactsArgs, ServerImport, ServerList, ServerRemove, ServerRotateToken, ServerTagAdd,
+    ServerTagList, ServerTagRemove, ServerTest, ServerTrust
+};
 
+
+// Parsed once at startup so variant sizes don't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Parser)]
 pub enum Cpcm {
     // Initialize directories
@@ -12,14 +22,103 @@ pub enum Cpcm {
     Domain(DomainArgs),
 
     #[clap(subcommand, name = "server")]
-    Server(ServerSubcommand)
+    Server(ServerSubcommand),
+
+    // Domain changes recorded by sync across all servers
+    Changes(ChangesArgs),
+
+    #[clap(subcommand)]
+    Debug(DebugSubcommand),
+
+    // Encrypted API key storage
+    #[clap(subcommand)]
+    Secrets(SecretsSubcommand)
 }
 
+#[derive(Parser, Debug)]
+pub enum DomainSubcommand {
+    // Every recorded change to one domain
+    History(DomainHistory),
+
+    // Load a saved get_domain_info response for a server
+    Import(DomainImport)
+}
 
 
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug)]
 pub enum ServerSubcommand {
-    Add(ServerAdd)
+    Add(ServerAdd),
+
+    // Registered servers, never with their API keys
+    List(ServerList),
+
+    // Check reachability, TLS, WHM version and token ACLs
+    Test(ServerTest),
+
+    // Add or update servers from a CSV or YAML inventory
+    Import(ServerImport),
+
+    // Change a server's name, IP, user, hostname, group or API key
+    Edit(ServerEdit),
+
+    // Delete a server with its domains, history and cached responses
+    Remove(ServerRemove),
+
+    // Pin the certificate a server currently presents
+    Trust(ServerTrust),
+
+    // Replace API tokens with new ones holding the same ACLs
+    RotateToken(ServerRotateToken),
+
+    // Version, OS, load, disks and account count as last collected
+    Facts(ServerFactsArgs),
+
+    // key=value labels to select servers by
+    #[clap(subcommand)]
+    Tag(ServerTagSubcommand)
+}
+
+#[derive(Parser, Debug)]
+pub enum ServerTagSubcommand {
+    // Set tags on a server
+    Add(ServerTagAdd),
+
+    // Take tags off a server
+    Remove(ServerTagRemove),
+
+    // Show each server's tags
+    List(ServerTagList)
+}
+
+#[derive(Parser, Debug)]
+pub enum SecretsSubcommand {
+    // Set a passphrase if there isn't one and encrypt every plain API key
+    Migrate,
+
+    // Re-encrypt every API key under a new passphrase
+    Rekey,
+
+    // Keep the derived key around so later commands don't ask
+    Unlock(SecretsUnlock),
+
+    // Forget a cached unlock
+    Lock
+}
+
+#[derive(Parser, Debug)]
+pub enum DebugSubcommand {
+    // Re-run parsing and upsert on a server's newest cached response
+    Replay(DebugReplay)
+}
+
+// How listing commands print their results
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv
 }
 
 #[derive(Parser, Debug)]
@@ -27,3 +126,21 @@ pub struct InitSubcommand {
     #[arg(short, long)]
     pub force: bool
 }
+
+// Ages like 90s, 30m, 12h, 7d or 2w. A bare number is seconds.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num.parse().map_err(|_| format!("{} is not an age like 12h or 7d", s))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        u => return Err(format!("Unknown unit {} in {}. Use s, m, h, d or w", u, s))
+    };
+
+    Ok(Duration::from_secs(num * secs))
+}
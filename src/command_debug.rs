@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::command_domain::upsert_domains;
+use crate::config::Config;
+use crate::database;
+use crate::domain_diff::DomainDiff;
+use crate::global_paths::GlobalPaths;
+use crate::response_cache::ResponseCache;
+use crate::sqlite_types::{DomainRow, ServerRow};
+use crate::whm_client::WhmClient;
+
+#[derive(Debug, Args)]
+pub struct DebugReplay {
+    // Server whose newest cached get_domain_info response gets replayed
+    server: String,
+
+    // Show what would change without writing anything
+    #[arg(long)]
+    dry_run: bool
+}
+
+// Run the newest cached get_domain_info response for a server through the
+// same parsing and upsert as a sync, without going near the network. Rows
+// the parser throws away are listed along with why.
+pub fn run_debug_replay(args: DebugReplay, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let lastupdate = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.server)?;
+
+    let cached = ResponseCache::open(paths).latest(&server.name, "get_domain_info")?
+        .ok_or(format!("No cached get_domain_info response for {}. Set cache_responses in the config and sync it first",
+            server.name))?;
+    println!("Replaying {}", cached.path.display());
+
+    let body: Value = serde_json::from_str(&cached.read()?)
+        .map_err(|e| format!("Cached response is not valid JSON: {}", e))?;
+    let data = WhmClient::unpack("get_domain_info", body)?;
+
+    // Rows get dropped in two places, once when they don't deserialize and
+    // again when safe_unwrap finds a required field missing.
+    let (parsed, rejected) = WhmClient::split_domain_info(&data);
+    let mut problems: Vec<(String, String)> = rejected.iter()
+        .map(|(row, e)| (row["domain"].as_str().unwrap_or("<no domain>").to_string(), e.to_string()))
+        .collect();
+    let mut domains = Vec::new();
+    for row in parsed {
+        let name = row.domain.clone().unwrap_or("<no domain>".to_string());
+        match row.safe_unwrap() {
+            Ok(r) => domains.push(r),
+            Err(e) => problems.push((name, e.to_string()))
+        }
+    }
+
+    println!("{} rows usable, {} rejected", domains.len(), problems.len());
+    for (domain, why) in &problems {
+        println!("  ! {}: {}", domain, why);
+    }
+
+    if args.dry_run {
+        let current = DomainRow::select_by_server(&db, config, &server.name)?;
+        DomainDiff::new(&server.name, current, domains).print();
+    } else {
+        upsert_domains(&mut db, config, &server, domains, lastupdate)?;
+    }
+
+    Ok(())
+}
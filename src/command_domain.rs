@@ -1,178 +1,338 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
 
 
+use std::sync::Arc;
+
 use clap::Args;
-use reqwest::{header, ClientBuilder};
-use rusqlite::{Connection, params};
+use rusqlite::Connection;
 use serde_json::Value;
-use http::Method;
-use url::Url;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 use crate::global_paths::GlobalPaths;
+use crate::cli::{parse_age, DomainSubcommand};
+use crate::command_history::run_domain_history;
 use crate::config::Config;
-use crate::sqlite_types::DomainRow;
-use crate::sql_strings::DOMAINSYNC_UPSERT;
+use crate::database;
+use crate::domain_diff::DomainDiff;
+use crate::error_types::WhmError;
+use crate::response_cache::ResponseCache;
+use crate::secrets;
+use crate::server_facts::ServerFacts;
+use crate::sqlite_types::{DomainFilter, DomainRow, ServerRow};
+use crate::sql_strings::{DOMAINSYNC_REMOVE_STALE, DOMAINSYNC_UPSERT, DOMAIN_SELECT_WHERE};
+use crate::server_select::{last_sync_times, ServerSelection};
+use crate::whm_client::WhmClient;
 
 #[derive(Debug, Args)]
 pub struct DomainArgs {
+    #[command(subcommand)]
+    command: Option<DomainSubcommand>,
+
     #[arg(long)]
     sync: bool,
 
+    // Number of servers to sync at once. Defaults to sync_jobs from the config
+    #[arg(long, short)]
+    jobs: Option<usize>,
+
+    #[command(flatten)]
+    select: ServerSelection,
+
+    // Only sync servers whose last sync is older than this, e.g. 12h or 7d
+    #[arg(long, value_parser = parse_age)]
+    stale_only: Option<Duration>,
+
+    // Fetch and show what would change without writing anything
+    #[arg(long, requires = "sync")]
+    dry_run: bool,
+
     #[arg(long, short)]
     name: Option<String>,
+
+    // Filter expression, e.g. "php_version=ea-php74 and server~fra*"
+    #[arg(long, short, value_parser = DomainFilter::parse)]
+    filter: Option<DomainFilter>,
 }
 
 
+#[derive(Debug, Args)]
+pub struct DomainImport {
+    // Server the response came from. It has to exist already.
+    #[arg(long)]
+    server: String,
 
-async fn sync_domain_db(paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
-    let lastupdate = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().to_string();
-    let db = Connection::open(paths.dbfile())?;
+    // Saved whmapi1 get_domain_info JSON
+    file: PathBuf
+}
 
-    // I'm fine panicing if the domain table name doesn't exist
-    // because otherwise something has gone horribly wrong.
+// Outcome of syncing a single server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncStatus {
+    Ok,
+    AuthFailure,
+    Timeout,
+    TlsError,
+    BadJson,
+    ConfigError,
+    Failed
+}
 
-    let remove_sql = format!("DELETE FROM {} WHERE lastupdated < {}", config.tabname_domain(), &lastupdate);
-    let mut upsert_stmt = db.prepare(&DOMAINSYNC_UPSERT(config))?;
-    let mut remove_stmt =db.prepare(&remove_sql)?;
-    let mut statement = db.prepare("SELECT name,ip,hostname,user,apikey FROM servers")?;
-    let mut servers = statement.query(params![])?;
-    let client = ClientBuilder::new()
-        .danger_accept_invalid_certs(true)
-        .build()?;
-
-    // Each row is one server
-    while let Some(row) = servers.next()? {
-        let server_name: String = row.get::<_, String>("name")?.clone();
-        let server_ip: String = row.get::<_, String>("ip")?.clone();
-        let user: String = row.get::<_, String>("user")?.clone();
-        // query endpoint
-        let whmurl = Url::parse(
-            &format!("https://{}:2087/json-api/get_domain_info?api.version=1", row.get::<_, String>("ip")?).to_string()
-        )?;
-        let authkey = row.get::<_, String>("apikey")?;
-        let req = client.request(Method::GET, whmurl)
-            .header(
-                header::HeaderName::from_static("authorization"),
-                header::HeaderValue::from_str(&format!("whm {}:{}", user, authkey))?
-            )
-            .build()?;
-
-        log::debug!("Sending request to {} via IP {}", server_name, server_ip);
-        log::debug!("{}", format!("Request details {:?}", req));
-        let resp = match client.execute(req).await {
-            Ok(r) => {
-                log::debug!("Got response {:?}", r);
-                r.json::<Value>().await?
+impl From<&WhmError> for SyncStatus {
+    fn from(e: &WhmError) -> Self {
+        if e.is_auth() {
+            SyncStatus::AuthFailure
+        } else if e.is_timeout() {
+            SyncStatus::Timeout
+        } else if e.is_tls() {
+            SyncStatus::TlsError
+        } else if let WhmError::Decode(_) = e {
+            SyncStatus::BadJson
+        } else if let WhmError::Config(_) = e {
+            SyncStatus::ConfigError
+        } else {
+            SyncStatus::Failed
+        }
+    }
+}
+
+impl fmt::Display for SyncStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SyncStatus::Ok => "ok",
+            SyncStatus::AuthFailure => "auth failure",
+            SyncStatus::Timeout => "timeout",
+            SyncStatus::TlsError => "tls error",
+            SyncStatus::BadJson => "bad json",
+            SyncStatus::ConfigError => "config error",
+            SyncStatus::Failed => "failed"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct SyncReport {
+    pub server: String,
+    pub status: SyncStatus,
+    pub domains: usize,
+    pub duration: Duration,
+    // Only filled in on a dry run
+    pub diff: Option<DomainDiff>
+}
+
+async fn sync_domain_db(args: &DomainArgs, paths: &GlobalPaths, config: &Config) -> Result<Vec<SyncReport>, Box<dyn Error>> {
+    let jobs = args.jobs.unwrap_or(config.sync_jobs());
+    let lastupdate = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut db = database::open(paths, config)?;
+    let mut servers = args.select.select(&db, config)?;
+
+    if let Some(age) = args.stale_only {
+        let cutoff = lastupdate - age.as_secs() as i64;
+        let synced = last_sync_times(&db, config)?;
+        servers.retain(|s| synced.get(&s.name).is_none_or(|t| *t < cutoff));
+    }
+    secrets::open_keys(&db, &mut servers)?;
+
+    // Fan out one task per server, the semaphore keeps at most `jobs` of them
+    // talking to WHM at any one time.
+    log::debug!("Syncing {} servers with {} jobs", servers.len(), jobs);
+    let limit = Arc::new(Semaphore::new(jobs.max(1)));
+    // A dry run leaves no trace, so no cached responses and no facts
+    let cache = ResponseCache::from_config(paths, config).filter(|_| !args.dry_run);
+    let collect_facts = config.collect_facts() && !args.dry_run;
+    let mut tasks = JoinSet::new();
+    for server in servers {
+        let limit = limit.clone();
+        let client = WhmClient::new(&server, config).map(|c| c.with_cache(cache.clone()));
+        tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await;
+            let started = Instant::now();
+            let (domains, facts) = match client {
+                Ok(client) => {
+                    let domains = client.get_domain_info().await;
+                    // Not worth asking a server that just failed
+                    let facts = match domains.is_ok() && collect_facts {
+                        true => Some(ServerFacts::collect(&client, lastupdate).await),
+                        false => None
+                    };
+                    (domains, facts)
+                },
+                Err(e) => (Err(e), None)
+            };
+            (server, domains, facts, started.elapsed())
+        });
+    }
+
+    // Write each server's domains as soon as it answers
+    let mut reports = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        let (server, domains, facts, duration) = joined?;
+        match facts {
+            Some(Ok(f)) => f.upsert(&db)?,
+            Some(Err(e)) => log::warn!("{}: unable to collect facts. {}", server.name, e),
+            _ => ()
+        }
+        let report = match domains {
+            Ok(d) => {
+                let count = d.len();
+                let diff = if args.dry_run {
+                    let current = DomainRow::select_by_server(&db, config, &server.name)?;
+                    Some(DomainDiff::new(&server.name, current, DomainRow::safe_unwrap_all(d)))
+                } else {
+                    upsert_domains(&mut db, config, &server, d, lastupdate)?;
+                    None
+                };
+                SyncReport { server: server.name, status: SyncStatus::Ok, domains: count, duration, diff }
             }
             Err(e) => {
-                log::error!("{:?}", e);
-                std::process::exit(2);
+                log::error!("{}: {}", server.name, e);
+                SyncReport { server: server.name, status: SyncStatus::from(&e), domains: 0, duration, diff: None }
             }
         };
+        reports.push(report);
+    };
 
-        log::debug!("Response data\n{:?}", resp);
-        let domains = match resp["data"]["domains"].as_array() {
-            Some(x) => {
-                let domains = x
-                    .into_iter()
-                    .inspect(|&x| log::debug!("{:?}", x))
-                    .map(|x| {
-                        let v = serde_json::from_value::<DomainRow>(x.clone());
-                        if v.is_err() {
-                            log::debug!("Unable to convert row! {}", v.err().unwrap());
-                            return None
-                        } else {
-                            return v.ok()
-                        }
-
-                    })
-                    .collect::<Vec<Option<DomainRow>>>();
-                domains
-            }
-            None => {
-                let domains: Vec<Option<DomainRow>> = Vec::new();
-                domains
-            }
-            };
-        //let domains = serde_json::from_value::<Vec<DomainRow>>(resp["data"]["domains"].clone())?;
-
-        // Perform upsert. Good lord why is this so annoying just implement
-        // rusqlite::Params for std::vec::Vec.
-        for domain_row in domains {
-            if let Some(domain_row) = domain_row {
-                let domain_row_result = domain_row.safe_unwrap();
-                if let Err(e) = domain_row_result {
-                    log::error!("Unable to unwrap row! {:?}", e);
-                    continue;
-                }
-                let safe_domain_row = domain_row_result.unwrap();
-
-                log::debug!("Inserting row {:?}", &safe_domain_row);
-                let u = upsert_stmt.execute(rusqlite::named_params! {
-                    ":docroot": safe_domain_row.docroot.unwrap(),
-                    ":domain": safe_domain_row.domain.unwrap(),
-                    ":domain_type": safe_domain_row.domain_type.unwrap(),
-                    ":ipv4": safe_domain_row.ipv4.unwrap(),
-                    ":ipv4_ssl": safe_domain_row.ipv4_ssl.unwrap(),
-                    ":ipv6": safe_domain_row.ipv6.unwrap_or("NULL".to_string()),
-                    ":ipv6_is_dedicated": safe_domain_row.ipv6_is_dedicated.unwrap_or(0),
-                    ":modsecurity_enabled": safe_domain_row.modsecurity_enabled.unwrap(),
-                    ":parent_domain": safe_domain_row.parent_domain.unwrap(),
-                    ":php_version": safe_domain_row.php_version.unwrap(),
-                    ":port": safe_domain_row.port.unwrap(),
-                    ":port_ssl": safe_domain_row.port_ssl.unwrap(),
-                    ":user": safe_domain_row.user.unwrap(),
-                    ":user_owner": safe_domain_row.user_owner.unwrap(),
-                    ":server_name": server_name,
-                    ":server_ip": server_ip,
-                    ":lastupdate": lastupdate
-                })?;
-
-                log::debug!("Inserted row with status code {u}");
-            } else {
-                log::debug!("Unable to parse row(s)!");
-            }
-        }
+    reports.sort_by(|a, b| a.server.cmp(&b.server));
+    Ok(reports)
+}
 
+fn print_sync_summary(reports: &[SyncReport]) {
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["server", "status", "domains", "duration"]);
+    for r in reports {
+        builder.push_record([
+            r.server.clone(),
+            r.status.to_string(),
+            r.domains.to_string(),
+            format!("{:.2}s", r.duration.as_secs_f64())
+        ]);
+    }
 
-    };
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+
+    println!("{}", table);
+}
+
+// Upsert one server's domains and drop the ones it no longer reports. This
+// all happens in one transaction and never touches other servers' rows, so a
+// server that failed to answer keeps whatever we had for it. Whatever changed
+// is recorded in domain_history in the same transaction.
+pub(crate) fn upsert_domains(db: &mut Connection, config: &Config, server: &ServerRow, domains: Vec<DomainRow>, lastupdate: i64)
+-> Result<(), Box<dyn Error>> {
+
+    // I'm fine panicing if the domain table name doesn't exist
+    // because otherwise something has gone horribly wrong.
+    let tx = db.transaction()?;
+    let mut upsert_stmt = tx.prepare(&DOMAINSYNC_UPSERT(config))?;
+    let mut remove_stmt = tx.prepare(&DOMAINSYNC_REMOVE_STALE(config))?;
+
+    let domains = DomainRow::safe_unwrap_all(domains);
+    let current = DomainRow::select_by_server(&tx, config, &server.name)?;
+    let diff = DomainDiff::new(&server.name, current, domains.clone());
+
+    // Perform upsert. Good lord why is this so annoying just implement
+    // rusqlite::Params for std::vec::Vec.
+    for safe_domain_row in domains {
+        log::debug!("Inserting row {:?}", &safe_domain_row);
+        let u = upsert_stmt.execute(rusqlite::named_params! {
+            ":docroot": safe_domain_row.docroot.unwrap(),
+            ":domain": safe_domain_row.domain.unwrap(),
+            ":domain_type": safe_domain_row.domain_type.unwrap(),
+            ":ipv4": safe_domain_row.ipv4.unwrap(),
+            ":ipv4_ssl": safe_domain_row.ipv4_ssl.unwrap(),
+            ":ipv6": safe_domain_row.ipv6.unwrap_or("NULL".to_string()),
+            ":ipv6_is_dedicated": safe_domain_row.ipv6_is_dedicated.unwrap_or(0),
+            ":modsecurity_enabled": safe_domain_row.modsecurity_enabled.unwrap(),
+            ":parent_domain": safe_domain_row.parent_domain.unwrap(),
+            ":php_version": safe_domain_row.php_version.unwrap(),
+            ":port": safe_domain_row.port.unwrap(),
+            ":port_ssl": safe_domain_row.port_ssl.unwrap(),
+            ":user": safe_domain_row.user.unwrap(),
+            ":user_owner": safe_domain_row.user_owner.unwrap(),
+            ":server_name": server.name,
+            ":server_ip": server.ip,
+            ":lastupdate": lastupdate
+        })?;
+
+        log::debug!("Inserted row with status code {u}");
+    }
+
+    // Finally clean up outdated rows. Go by the diff rather than lastupdated,
+    // two syncs within the same second would otherwise leave them behind.
+    for row in &diff.removed {
+        remove_stmt.execute(rusqlite::named_params! {
+            ":server_name": server.name,
+            ":domain": row.domain
+        })?;
+    }
+    log::debug!("Removed {} outdated rows for {}", diff.removed.len(), server.name);
 
-    // Finally clean up outdated rows
-    remove_stmt.execute(params![])?;
+    diff.record_history(&tx, lastupdate)?;
+
+    drop(upsert_stmt);
+    drop(remove_stmt);
+    tx.commit()?;
 
     Ok(())
 }
 
 
-fn find_and_print_domains(name: String, paths: &GlobalPaths, config: &Config)
+// Same as a live sync of one server except the response comes from a file.
+// Takes either the whole response or just its `data` member.
+fn import_domains(args: DomainImport, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let lastupdate = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.server)?;
+
+    let body: Value = serde_json::from_str(&fs::read_to_string(&args.file)?)
+        .map_err(|e| format!("{} is not valid JSON: {}", args.file.display(), e))?;
+    let data = if body.get("metadata").is_some() {
+        WhmClient::unpack("get_domain_info", body)?
+    } else if body.get("data").is_some() {
+        body["data"].clone()
+    } else {
+        body
+    };
+    if !data["domains"].is_array() {
+        return Err(format!("{} has no domains list. Is it a get_domain_info response?", args.file.display()).into())
+    }
+
+    let domains = WhmClient::parse_domain_info(&data);
+    println!("Importing {} domains for {}", domains.len(), server.name);
+    upsert_domains(&mut db, config, &server, domains, lastupdate)?;
+
+    Ok(())
+}
+
+fn find_and_print_domains(filter: DomainFilter, paths: &GlobalPaths, config: &Config)
 -> Result<(), Box<dyn Error>> {
 
-    log::debug!("Filtering domains containing  {}", &name);
-    let db = Connection::open(paths.dbfile())?;
+    log::debug!("Filtering domains with {:?}", &filter);
+    let db = database::open(paths, config)?;
     
     log::debug!("Connected to database");
-    let filter_sql = format!("SELECT * FROM {} WHERE domain LIKE '%{}%';", config.tabname_domain(), name.trim());
-    let mut filter_stmt = db.prepare(&filter_sql)?;
+    let (where_clause, values) = filter.to_sql();
+    let mut filter_stmt = db.prepare(&DOMAIN_SELECT_WHERE(config, &where_clause))?;
 
-    let mut results = filter_stmt.query(params![])?;
+    let mut results = filter_stmt.query(rusqlite::params_from_iter(values))?;
     let mut builder = tabled::builder::Builder::new();
 
     builder.push_record(DomainRow::header_str());
     log::debug!("Retrieved required rows. Printing rows.");
-    while let Ok(rowop) = results.next() {
-        if let Some(row) = rowop {
-            log::debug!("Found row {:?}", row);
-            builder.push_record(DomainRow::from_row(row)?.as_vec());
-        } else {
-            break
-        }
+    while let Some(row) = results.next()? {
+        log::debug!("Found row {:?}", row);
+        builder.push_record(DomainRow::from_row(row)?.as_vec());
     }
 
     let mut table = builder.build();
     table.with(tabled::settings::Style::rounded());
 
-    println!("{}", table.to_string());
+    println!("{}", table);
     Ok(())
 }
 
@@ -180,18 +340,38 @@ pub async fn run_domain(args: DomainArgs, paths: &GlobalPaths, config: &Config)
     -> Result<(), Box<dyn Error>> { 
     dbg!(&args, &paths);
 
+    if let Some(command) = args.command {
+        return match command {
+            DomainSubcommand::History(h) => run_domain_history(h, paths, config),
+            DomainSubcommand::Import(i) => import_domains(i, paths, config)
+        }
+    }
+
     if args.sync {
         log::info!("Syncing domains");
-        let r = sync_domain_db(paths, config).await;
+        let reports = sync_domain_db(&args, paths, config).await?;
         log::info!("Synced domains. Exiting");
-        return r
+
+        for diff in reports.iter().filter_map(|r| r.diff.as_ref()) {
+            diff.print();
+        }
+        print_sync_summary(&reports);
+        let failed = reports.iter().filter(|r| r.status != SyncStatus::Ok).count();
+        if failed > 0 {
+            return Err(format!("{} of {} servers failed to sync", failed, reports.len()).into())
+        }
+        return Ok(())
     }
 
     
-    match args.name {
-        Some(n) => find_and_print_domains(n, paths, config)?,
-        None => ()
+    let filter = match (args.name, args.filter) {
+        (Some(n), Some(f)) => Some(DomainFilter::domain_contains(n.trim()).and(f)),
+        (Some(n), None) => Some(DomainFilter::domain_contains(n.trim())),
+        (None, f) => f
     };
+    if let Some(f) = filter {
+        find_and_print_domains(f, paths, config)?
+    }
 
     Ok(())
 }
@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use serde_json::{Map, Value};
+
+use crate::cli::parse_age;
+use crate::config::Config;
+use crate::database;
+use crate::global_paths::GlobalPaths;
+use crate::sql_strings::HISTORY_SELECT;
+
+#[derive(Debug, Args)]
+pub struct DomainHistory {
+    domain: String
+}
+
+#[derive(Debug, Args)]
+pub struct ChangesArgs {
+    // How far back to look, e.g. 12h or 7d
+    #[arg(long, value_parser = parse_age, default_value = "1d")]
+    since: Duration,
+
+    // Only changes seen on this server
+    #[arg(long)]
+    server: Option<String>
+}
+
+pub fn run_domain_history(args: DomainHistory, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    print_history(Some(args.domain.trim()), None, None, paths, config)
+}
+
+pub fn run_changes(args: ChangesArgs, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let since = now.saturating_sub(args.since.as_secs()) as i64;
+
+    print_history(None, Some(since), args.server.as_deref(), paths, config)
+}
+
+fn print_history(domain: Option<&str>, since: Option<i64>, server: Option<&str>, paths: &GlobalPaths, config: &Config)
+-> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let mut stmt = db.prepare(&HISTORY_SELECT())?;
+    let mut rows = stmt.query(rusqlite::named_params! {
+        ":domain": domain,
+        ":since": since,
+        ":server_name": server
+    })?;
+
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["time", "server", "domain", "action", "changes"]);
+    while let Some(row) = rows.next()? {
+        let action: String = row.get("action")?;
+        let old = parse_values(row.get("old_values")?);
+        let new = parse_values(row.get("new_values")?);
+
+        builder.push_record([
+            row.get::<_, String>("time")?,
+            row.get::<_, String>("server_name")?,
+            row.get::<_, String>("domain")?,
+            action.clone(),
+            describe(&action, &old, &new)
+        ]);
+    }
+
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+
+    println!("{}", table);
+    Ok(())
+}
+
+fn parse_values(json: Option<String>) -> Map<String, Value> {
+    json.and_then(|j| serde_json::from_str::<Map<String, Value>>(&j).ok())
+        .unwrap_or_default()
+}
+
+// Updates list every field that changed. Inserts and deletes only mention
+// the bits people usually care about.
+fn describe(action: &str, old: &Map<String, Value>, new: &Map<String, Value>) -> String {
+    let get = |m: &Map<String, Value>, k: &str| m.get(k).and_then(|v| v.as_str()).unwrap_or("NULL").to_string();
+
+    match action {
+        "update" => new.keys()
+            .map(|k| format!("{}: {} -> {}", k, get(old, k), get(new, k)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        "insert" => format!("user {}, php {}", get(new, "user"), get(new, "php_version")),
+        "delete" => format!("user {}, php {}", get(old, "user"), get(old, "php_version")),
+        _ => String::new()
+    }
+}
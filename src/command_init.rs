@@ -1,5 +1,6 @@
 use crate::global_paths::GlobalPaths;
 use crate::config::Config;
+use crate::database;
 use crate::sql_strings::SQLSCHEMA;
 use crate::cli::InitSubcommand;
 
@@ -48,9 +49,8 @@ pub fn initialize(args: &InitSubcommand, paths: &GlobalPaths) -> Result<Config,
 
     log::debug!("Creating SQLite database");
     // Create SQLite database;
-    let dbconn = rusqlite::Connection::open(paths.dbfile())?;
+    let mut dbconn = rusqlite::Connection::open(paths.dbfile())?;
     let _ = SQLSCHEMA(&defaultcfg).split("-- Statement\n").filter(|&x| !x.is_empty())
-        .into_iter()
         .inspect(|s| log::debug!("Running {}", s))
         .map(|s| {
             let r = dbconn.execute(s, rusqlite::params![]);
@@ -61,7 +61,8 @@ pub fn initialize(args: &InitSubcommand, paths: &GlobalPaths) -> Result<Config,
         })
         .collect::<Vec<_>>();
 
-
+    log::debug!("Applying migrations");
+    database::migrate(&mut dbconn, &defaultcfg)?;
 
     defaultcfg.write_file(paths)?;
 
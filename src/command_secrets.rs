@@ -0,0 +1,129 @@
+use std::error::Error;
+use std::time::Duration;
+
+use clap::Args;
+use rusqlite::Connection;
+
+use crate::cli::parse_age;
+use crate::config::Config;
+use crate::database;
+use crate::global_paths::GlobalPaths;
+use crate::secrets::{self, Vault};
+use crate::sqlite_types::ServerRow;
+use crate::sql_strings::{SERVER_PENDING_KEYS, SERVER_SET_APIKEY, SERVER_SET_PENDING_APIKEY};
+
+#[derive(Debug, Args)]
+pub struct SecretsUnlock {
+    // How long the unlock lasts, e.g. 30m or 8h
+    #[arg(long, value_parser = parse_age, default_value = "8h")]
+    ttl: Duration
+}
+
+// A token left half rotated by server rotate-token
+struct PendingKey {
+    name: String,
+    ip: String,
+    apikey: String
+}
+
+fn pending_keys(db: &Connection, config: &Config) -> Result<Vec<PendingKey>, Box<dyn Error>> {
+    let mut stmt = db.prepare(&SERVER_PENDING_KEYS(config))?;
+    let keys = stmt.query_map([], |r| Ok(PendingKey { name: r.get(0)?, ip: r.get(1)?, apikey: r.get(2)? }))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(keys)
+}
+
+// Encrypt every plain API key. The first run sets the passphrase, later runs
+// pick up keys added before it or written by an older cpcm.
+pub fn run_secrets_migrate(paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let existing = secrets::unlock(&db)?;
+
+    let tx = db.transaction()?;
+    let vault = match existing {
+        Some(v) => v,
+        None => secrets::create(&tx, &secrets::new_passphrase()?)?
+    };
+    let mut count = 0;
+    for server in ServerRow::select_all(&tx, config)?.iter().filter(|s| !secrets::is_encrypted(&s.apikey)) {
+        tx.execute(&SERVER_SET_APIKEY(config), rusqlite::named_params! {
+            ":apikey": vault.encrypt(&server.apikey)?,
+            ":name": server.name,
+            ":ip": server.ip
+        })?;
+        count += 1;
+    }
+    for pending in pending_keys(&tx, config)?.iter().filter(|p| !secrets::is_encrypted(&p.apikey)) {
+        tx.execute(&SERVER_SET_PENDING_APIKEY(config), rusqlite::named_params! {
+            ":apikey": vault.encrypt(&pending.apikey)?,
+            ":name": pending.name,
+            ":ip": pending.ip
+        })?;
+    }
+    tx.commit()?;
+    println!("Encrypted {} API keys", count);
+
+    Ok(())
+}
+
+// Re-encrypt every key under a new passphrase and salt in one transaction
+pub fn run_secrets_rekey(paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let old = secrets::unlock(&db)?
+        .ok_or("API keys aren't encrypted yet. Run cpcm secrets migrate first")?;
+    let passphrase = secrets::new_passphrase()?;
+
+    let tx = db.transaction()?;
+    let new = secrets::create(&tx, &passphrase)?;
+    let mut count = 0;
+    for server in ServerRow::select_all(&tx, config)? {
+        let plain = match secrets::is_encrypted(&server.apikey) {
+            true => old.decrypt(&server.apikey).map_err(|e| format!("{}: {}", server.name, e))?,
+            false => server.apikey.clone()
+        };
+        tx.execute(&SERVER_SET_APIKEY(config), rusqlite::named_params! {
+            ":apikey": new.encrypt(&plain)?,
+            ":name": server.name,
+            ":ip": server.ip
+        })?;
+        count += 1;
+    }
+    for pending in pending_keys(&tx, config)? {
+        let plain = match secrets::is_encrypted(&pending.apikey) {
+            true => old.decrypt(&pending.apikey).map_err(|e| format!("{}: {}", pending.name, e))?,
+            false => pending.apikey.clone()
+        };
+        tx.execute(&SERVER_SET_PENDING_APIKEY(config), rusqlite::named_params! {
+            ":apikey": new.encrypt(&plain)?,
+            ":name": pending.name,
+            ":ip": pending.ip
+        })?;
+    }
+    tx.commit()?;
+
+    // Whatever was cached is for the old passphrase
+    secrets::forget_unlock(&db)?;
+    println!("Re-encrypted {} API keys", count);
+
+    Ok(())
+}
+
+pub fn run_secrets_unlock(args: SecretsUnlock, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let vault: Vault = secrets::unlock(&db)?
+        .ok_or("API keys aren't encrypted yet. Run cpcm secrets migrate first")?;
+
+    let path = secrets::cache_unlock(&db, &vault, args.ttl)?;
+    println!("Unlocked for {}s ({})", args.ttl.as_secs(), path.display());
+
+    Ok(())
+}
+
+pub fn run_secrets_lock(paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    secrets::forget_unlock(&db)?;
+    println!("Locked");
+
+    Ok(())
+}
@@ -1,9 +1,36 @@
 use clap::Args;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use crate::apikey::{ApiKeyArgs, KeySource};
+use crate::cli::OutputFormat;
+use crate::command_domain::SyncStatus;
 use crate::global_paths::GlobalPaths;
-use crate::config::Config;
-use crate::sql_strings::SERVERADD_UPSERT;
-use rusqlite::{params, Connection};
+use crate::config::{parse_max_retries, parse_requests_per_second, Config};
+use crate::database;
+use crate::error_types::WhmError;
+use crate::server_inventory::{read_inventory, InventoryEntry, InventoryFormat, SkippedRow};
+use crate::server_select::{check_tag_part, domain_stats, tags_by_server, ServerSelection};
+use crate::response_cache::ResponseCache;
+use crate::secrets::{self, Vault};
+use crate::server_facts::{version_matches, version_older_than, ServerFacts};
+use crate::sqlite_types::{DomainRow, ServerRow};
+use crate::sql_strings::{
+    DOMAIN_DELETE_BY_SERVER, DOMAIN_MOVE_SERVER, FACTS_DELETE_BY_SERVER, FACTS_RENAME_SERVER, HISTORY_DELETE_BY_SERVER, HISTORY_RENAME_SERVER, SERVERADD_UPSERT,
+    SERVER_DELETE, SERVER_DELETE_DUPLICATES, SERVER_SET_TLS, SERVER_UPDATE, TOKEN_CLEAR_PENDING, TOKEN_CLEAR_STALE,
+    TAGS_DELETE, TAGS_DELETE_BY_SERVER, TAGS_RENAME_SERVER, TAGS_UPSERT, TOKEN_PROMOTE, TOKEN_SET_PENDING,
+    TOKEN_STATE_SELECT
+};
+use crate::tls::{self, TlsMode};
+use crate::transport::Transport;
+use crate::whm_client::WhmClient;
 #[derive(Debug, Args)]
 pub struct ServerAdd {
     #[arg(short, long)]
@@ -15,25 +42,1077 @@ pub struct ServerAdd {
     #[arg(long)]
     hostname: Option<String>,
     #[arg(short, long)]
-    group: Option<String>
+    group: Option<String>,
+    // Verify the server against this PEM CA bundle instead of the system store
+    #[arg(long, conflicts_with = "tls_fingerprint")]
+    tls_ca: Option<PathBuf>,
+    // Pin the server's certificate by its SHA-256 fingerprint
+    #[arg(long)]
+    tls_fingerprint: Option<String>,
+    #[arg(long, default_value = "https", value_parser = ["https", "http"])]
+    scheme: String,
+    #[arg(long, default_value_t = 2087)]
+    port: u16,
+    // Path in front of /json-api, for servers behind a reverse proxy
+    #[arg(long, default_value = "")]
+    base_path: String,
+    // Connect by hostname and use the IP only when that fails
+    #[arg(long, requires = "hostname")]
+    use_hostname: bool,
+    // Overrides for the request settings in the config
+    #[arg(long)]
+    connect_timeout_secs: Option<u64>,
+    #[arg(long)]
+    read_timeout_secs: Option<u64>,
+    #[arg(long, value_parser = parse_max_retries)]
+    max_retries: Option<u32>,
+    #[arg(long)]
+    retry_backoff_ms: Option<u64>,
+    #[arg(long, value_parser = parse_requests_per_second)]
+    requests_per_second: Option<f64>,
+    // Reach the server through an http://, https://, socks5:// or socks5h:// proxy
+    #[arg(long, conflicts_with = "ssh_via")]
+    proxy: Option<String>,
+    // Reach the server through an ssh local forward from this destination,
+    // e.g. "jump@bastion.example.com" or "-p 2222 jump@bastion"
+    #[arg(long, allow_hyphen_values = true)]
+    ssh_via: Option<String>,
+    // Don't run server test on it afterwards
+    #[arg(long)]
+    no_test: bool,
+    #[command(flatten)]
+    key: ApiKeyArgs
+}
+
+#[derive(Debug, Args)]
+pub struct ServerList {
+    #[command(flatten)]
+    select: ServerSelection,
+
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: OutputFormat
+}
+
+// One line of `server list`. Deliberately has no apikey.
+#[derive(Debug, Serialize)]
+struct ServerListing {
+    name: String,
+    ip: String,
+    hostname: Option<String>,
+    group: Option<String>,
+    domains: i64,
+    // UTC
+    last_sync: Option<String>
+}
+
+#[derive(Debug, Args)]
+pub struct ServerTest {
+    // Server to test
+    #[arg(required_unless_present_any = ["all", "server", "group", "tag"])]
+    name: Option<String>,
+
+    // Test every server
+    #[arg(long, conflicts_with = "name")]
+    all: bool,
+
+    #[command(flatten)]
+    select: ServerSelection
+}
+
+#[derive(Debug, Args)]
+pub struct ServerImport {
+    // CSV with a header row, or a YAML list, with name, ip, user, hostname,
+    // group and apikey (env:NAME, file:PATH or prompt)
+    file: PathBuf,
+
+    // Defaults to going by the file extension
+    #[arg(long, value_enum)]
+    format: Option<InventoryFormat>
+}
+
+#[derive(Debug, Args)]
+pub struct ServerEdit {
+    name: String,
+    // New name for the server
+    #[arg(long)]
+    rename: Option<String>,
+    #[arg(short, long)]
+    ip: Option<String>,
+    #[arg(short, long)]
+    user: Option<String>,
+    // An empty value clears it
+    #[arg(long)]
+    hostname: Option<String>,
+    // An empty value clears it
+    #[arg(short, long)]
+    group: Option<String>,
+    // Prompt for a new API key
+    #[arg(long, conflicts_with_all = ["apikey_stdin", "apikey_file", "apikey_env"])]
+    apikey: bool,
+    #[command(flatten)]
+    key: ApiKeyArgs
+}
+
+#[derive(Debug, Args)]
+pub struct ServerRemove {
+    name: String,
+    // Keep the server's domain change records
+    #[arg(long)]
+    keep_history: bool,
+    // Remove without asking
+    #[arg(short, long)]
+    yes: bool
+}
+
+#[derive(Debug, Args)]
+pub struct ServerFactsArgs {
+    #[command(flatten)]
+    select: ServerSelection,
+
+    // Ask the servers again before showing anything
+    #[arg(long)]
+    refresh: bool,
+
+    // Only servers on this version, e.g. 110 or 11.110.0
+    #[arg(long)]
+    version: Option<String>,
+
+    // Only servers on a version older than this, e.g. 120
+    #[arg(long, value_name = "VERSION")]
+    older_than: Option<String>,
+
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: OutputFormat
+}
+
+// One line of `server facts` as CSV, which can't hold the list of disks.
+// Only the fullest one is kept.
+#[derive(Debug, Serialize)]
+struct FactsListing {
+    server: String,
+    version: String,
+    tier: Option<String>,
+    os: Option<String>,
+    hostname: Option<String>,
+    load_1: Option<f64>,
+    load_5: Option<f64>,
+    load_15: Option<f64>,
+    accounts: Option<i64>,
+    fullest_disk: Option<String>,
+    fullest_disk_percentage: Option<f64>,
+    // UTC
+    collected: String
+}
+
+#[derive(Debug, Args)]
+pub struct ServerRotateToken {
+    // Server whose token to rotate
+    #[arg(required_unless_present_any = ["all", "server", "group", "tag"])]
+    name: Option<String>,
+
+    // Rotate every server's token
+    #[arg(long, conflicts_with = "name")]
+    all: bool,
+
+    #[command(flatten)]
+    select: ServerSelection,
+
+    // WHM name of the current token, for servers whose token cpcm didn't
+    // create and so can't revoke by itself
+    #[arg(long, value_name = "NAME")]
+    old_token_name: Option<String>
+}
+
+#[derive(Debug, Args)]
+pub struct ServerTagAdd {
+    name: String,
+
+    // key=value, replacing any value the key already has
+    #[arg(required = true, value_parser = parse_tag)]
+    tags: Vec<(String, String)>
+}
+
+#[derive(Debug, Args)]
+pub struct ServerTagRemove {
+    name: String,
+
+    // key, or key=value to remove it only while it has that value
+    #[arg(required = true)]
+    tags: Vec<String>
+}
+
+#[derive(Debug, Args)]
+pub struct ServerTagList {
+    #[command(flatten)]
+    select: ServerSelection
+}
+
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let (k, v) = s.split_once('=').ok_or(format!("{} is not key=value", s))?;
+    let (k, v) = (k.trim(), v.trim());
+    check_tag_part("key", k)?;
+    check_tag_part("value", v)?;
+
+    Ok((k.to_string(), v.to_string()))
+}
+
+#[derive(Debug, Args)]
+pub struct ServerTrust {
+    name: String,
+    // Pin the certificate without asking
+    #[arg(short, long)]
+    yes: bool
 }
 
 
-pub fn run_server_add(server: ServerAdd, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+pub async fn run_server_add(server: ServerAdd, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
 
     log::debug!("Got server {:?}", &server);
-    // Prompt user for api key
-    let apikey = rpassword::prompt_password("API Key: ")?;
+    let tls = match (server.tls_ca, server.tls_fingerprint) {
+        (Some(ca), _) => TlsMode::CaBundle(ca.canonicalize()
+            .map_err(|e| format!("Unable to find CA bundle {}: {}", ca.display(), e))?),
+        (_, Some(fp)) => TlsMode::Pinned(tls::normalize_fingerprint(&fp)?),
+        _ => TlsMode::System
+    };
+    let transport = match (server.proxy, server.ssh_via) {
+        (Some(proxy), _) => Transport::from_proxy_url(&proxy)?,
+        (_, Some(target)) => Transport::Ssh(target),
+        _ => Transport::Direct
+    };
+
+    let db = database::open(paths, config)?;
+    check_ip_unchanged(&db, config, &server.name, &server.ip)?;
 
-    let db = Connection::open(paths.cpcmdbfile.path.clone())?;
-    let mut stmt = db.prepare(&SERVERADD_UPSERT(config))?;
+    let apikey = server.key.source().unwrap_or(KeySource::Prompt).read("API Key: ")?;
+    let row = ServerRow {
+        hostname: server.hostname,
+        group: server.group,
+        tls,
+        scheme: server.scheme,
+        port: server.port,
+        base_path: server.base_path,
+        use_hostname: server.use_hostname,
+        connect_timeout_secs: server.connect_timeout_secs,
+        read_timeout_secs: server.read_timeout_secs,
+        max_retries: server.max_retries,
+        retry_backoff_ms: server.retry_backoff_ms,
+        requests_per_second: server.requests_per_second,
+        transport,
+        ..ServerRow::new(server.name.clone(), server.ip, server.user, apikey)
+    };
+    upsert_server(&db, config, &row, secrets::unlock(&db)?.as_ref())?;
+
+    // The server stays either way, this is only so a bad key or ACL shows up
+    // now rather than at the next sync.
+    if !server.no_test {
+        let checks = check_servers(vec![row], config).await;
+        print_checks(&checks);
+        if checks.iter().any(|c| !c.passed()) {
+            eprintln!("Warning: {} was added but failed its test. Fix it with cpcm server edit", server.name);
+        }
+    }
 
-    log::debug!("Running {:?}", stmt);
-    stmt.execute(params![
-        server.name, server.ip, server.user, apikey.trim(),
-        server.hostname.unwrap_or("NULL".to_string()),
-        server.group.unwrap_or("NULL".to_string())
-    ])?;
-    
     Ok(())
 }
+
+// Insert a server or update the one with the same name and IP. Everything
+// that writes whole servers goes through here. The key is encrypted first
+// when there's a vault.
+fn upsert_server(db: &Connection, config: &Config, server: &ServerRow, vault: Option<&Vault>) -> Result<(), Box<dyn Error>> {
+    let (tls_mode, tls_ca_path, tls_fingerprint) = server.tls.as_columns();
+    let (transport, transport_target) = server.transport.as_columns();
+    let mut stmt = db.prepare_cached(&SERVERADD_UPSERT(config))?;
+
+    log::debug!("Upserting server {}", server.name);
+    stmt.execute(rusqlite::named_params! {
+        ":name": server.name,
+        ":ip": server.ip,
+        ":user": server.user,
+        ":apikey": Vault::seal(vault, &server.apikey)?,
+        ":hostname": server.hostname.as_deref().unwrap_or("NULL"),
+        ":group": server.group.as_deref().unwrap_or("NULL"),
+        ":tls_mode": tls_mode,
+        ":tls_ca_path": tls_ca_path,
+        ":tls_fingerprint": tls_fingerprint,
+        ":scheme": server.scheme,
+        ":port": server.port,
+        ":base_path": server.base_path,
+        ":use_hostname": server.use_hostname,
+        ":connect_timeout_secs": server.connect_timeout_secs,
+        ":read_timeout_secs": server.read_timeout_secs,
+        ":max_retries": server.max_retries,
+        ":retry_backoff_ms": server.retry_backoff_ms,
+        ":requests_per_second": server.requests_per_second,
+        ":transport": transport,
+        ":transport_target": transport_target
+    })?;
+
+    Ok(())
+}
+
+// An inventory only knows the basics, so a server that's already there keeps
+// its TLS, connection and transport settings. The key is only replaced, and
+// the token name forgotten, when it actually changed.
+fn update_imported(db: &Connection, config: &Config, mut existing: ServerRow, server: &ServerRow,
+    vault: Option<&Vault>) -> Result<(), Box<dyn Error>> {
+    secrets::decrypt_keys(vault, std::slice::from_mut(&mut existing))?;
+    let apikey = match existing.apikey == server.apikey {
+        true => None,
+        false => Some(Vault::seal(vault, &server.apikey)?)
+    };
+
+    log::debug!("Updating imported server {}", server.name);
+    db.execute(&SERVER_UPDATE(config), rusqlite::named_params! {
+        ":name": server.name,
+        ":ip": server.ip,
+        ":user": server.user,
+        ":hostname": server.hostname.as_deref().unwrap_or("NULL"),
+        ":group": server.group.as_deref().unwrap_or("NULL"),
+        ":apikey": apikey,
+        ":old_name": existing.name,
+        ":old_ip": existing.ip
+    })?;
+
+    Ok(())
+}
+
+// The upsert goes by (name, ip), so a new IP for a known name would leave a
+// second copy of the server behind. That's what server edit is for.
+fn check_ip_unchanged(db: &Connection, config: &Config, name: &str, ip: &str) -> Result<(), Box<dyn Error>> {
+    match ServerRow::select_by_name(db, config, name) {
+        Ok(existing) if existing.ip != ip => Err(format!(
+            "{} already exists with IP {}. Use cpcm server edit {} --ip {} to change it", name, existing.ip, name, ip
+        ).into()),
+        _ => Ok(())
+    }
+}
+
+// ACLs each cpcm feature needs from a token. `all` covers every one of them.
+const FEATURE_ACLS: &[(&str, &str)] = &[
+    ("domain sync", "list-accts"),
+    // api_token_create, api_token_list and api_token_revoke
+    ("rotate-token", "manage-api-tokens"),
+    // version, gethostname, systemloadavg, getdiskusage and get_os_info
+    ("server facts", "basic-system-info"),
+    // get_update_config is root only
+    ("server facts tier", "all")
+];
+
+// What server test found out about one server
+struct ServerCheck {
+    server: String,
+    tls: String,
+    version: Result<String, WhmError>,
+    // None when we never got far enough to ask
+    missing_acls: Option<Vec<String>>,
+}
+
+impl ServerCheck {
+    fn passed(&self) -> bool {
+        self.version.is_ok() && self.missing_acls.as_ref().is_some_and(|m| m.is_empty())
+    }
+}
+
+async fn check_server(server: ServerRow, client: Result<WhmClient, WhmError>) -> ServerCheck {
+    let tls = match server.scheme.as_str() {
+        "https" => server.tls.as_columns().0.to_string(),
+        _ => "none".to_string()
+    };
+    let client = match client {
+        Ok(c) => c,
+        Err(e) => return ServerCheck { server: server.name, tls, version: Err(e), missing_acls: None }
+    };
+
+    let version = client.version().await;
+    let tls = match &version {
+        Err(e) if e.is_tls() => "failed".to_string(),
+        _ => tls
+    };
+    let missing_acls = match &version {
+        Ok(_) => match client.myprivs().await {
+            Ok(acls) => Some(FEATURE_ACLS.iter()
+                .filter(|(_, acl)| !acls.iter().any(|a| a == "all" || a == acl))
+                .map(|(feature, acl)| format!("{} ({})", acl, feature))
+                .collect()),
+            Err(e) => {
+                log::error!("{}: unable to list ACLs. {}", server.name, e);
+                None
+            }
+        },
+        Err(_) => None
+    };
+
+    ServerCheck { server: server.name, tls, version, missing_acls }
+}
+
+// Same fan out as domain sync, at most sync_jobs servers at once
+async fn check_servers(servers: Vec<ServerRow>, config: &Config) -> Vec<ServerCheck> {
+    let limit = Arc::new(Semaphore::new(config.sync_jobs().max(1)));
+    let mut tasks = JoinSet::new();
+    for server in servers {
+        let limit = limit.clone();
+        let client = WhmClient::new(&server, config);
+        tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await;
+            check_server(server, client).await
+        });
+    }
+
+    let mut checks: Vec<ServerCheck> = tasks.join_all().await;
+    checks.sort_by(|a, b| a.server.cmp(&b.server));
+
+    checks
+}
+
+fn print_checks(checks: &[ServerCheck]) {
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["server", "status", "tls", "version", "acls"]);
+    for c in checks {
+        let (status, version) = match &c.version {
+            Ok(v) => ("ok".to_string(), v.clone()),
+            Err(e) => (format!("{}: {}", SyncStatus::from(e), e), String::new())
+        };
+        let acls = match &c.missing_acls {
+            Some(m) if m.is_empty() => "ok".to_string(),
+            Some(m) => format!("missing {}", m.join(", ")),
+            None => "unknown".to_string()
+        };
+        builder.push_record([c.server.clone(), status, c.tls.clone(), version, acls]);
+    }
+
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+    println!("{}", table);
+}
+
+// Check that each server answers, its certificate verifies and its token
+// holds the ACLs cpcm needs
+pub async fn run_server_test(args: ServerTest, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let mut servers = match &args.name {
+        Some(name) => vec![ServerRow::select_by_name(&db, config, name)?],
+        None => args.select.select(&db, config)?
+    };
+    secrets::open_keys(&db, &mut servers)?;
+    drop(db);
+
+    let checks = check_servers(servers, config).await;
+    print_checks(&checks);
+    let failed = checks.iter().filter(|c| !c.passed()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} servers failed", failed, checks.len()).into())
+    }
+
+    Ok(())
+}
+
+pub fn run_server_list(args: ServerList, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let stats = domain_stats(&db, config)?;
+    let mut servers: Vec<ServerListing> = args.select.select(&db, config)?
+        .into_iter()
+        .map(|s| {
+            let (domains, last_sync) = match stats.get(&s.name) {
+                Some((count, time)) => (*count, Some(time.clone())),
+                None => (0, None)
+            };
+            ServerListing { name: s.name, ip: s.ip, hostname: s.hostname, group: s.group, domains, last_sync }
+        })
+        .collect();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    match args.output {
+        OutputFormat::Table => {
+            let mut builder = tabled::builder::Builder::new();
+            builder.push_record(["name", "ip", "hostname", "group", "domains", "last sync"]);
+            for s in servers {
+                builder.push_record([
+                    s.name,
+                    s.ip,
+                    s.hostname.unwrap_or_default(),
+                    s.group.unwrap_or_default(),
+                    s.domains.to_string(),
+                    s.last_sync.unwrap_or("never".to_string())
+                ]);
+            }
+
+            let mut table = builder.build();
+            table.with(tabled::settings::Style::rounded());
+            println!("{}", table);
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&servers)?),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for s in servers {
+                writer.serialize(s)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+// Trust on first use. Fetch whatever certificate the server presents right
+// now and pin it, so later connections fail if it ever changes.
+pub async fn run_server_trust(args: ServerTrust, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.name)?;
+
+    if server.scheme != "https" {
+        return Err(format!("{} is not using https", server.name).into())
+    }
+
+    let fp = tls::fetch_fingerprint(server.connect_host(), server.port, &server.transport).await?;
+    println!("{} ({}) presented certificate with SHA-256 fingerprint", server.name, server.connect_host());
+    println!("{}", tls::display_fingerprint(&fp));
+
+    if let TlsMode::Pinned(old) = &server.tls {
+        if *old == fp {
+            println!("Already pinned.");
+            return Ok(())
+        }
+        println!("This replaces the pinned fingerprint {}", tls::display_fingerprint(old));
+    }
+
+    if !args.yes && !confirm("Pin this certificate?")? {
+        return Err("Certificate not pinned".into())
+    }
+
+    let (tls_mode, tls_ca_path, tls_fingerprint) = TlsMode::Pinned(fp).as_columns();
+    db.execute(&SERVER_SET_TLS(config), rusqlite::named_params! {
+        ":tls_mode": tls_mode,
+        ":tls_ca_path": tls_ca_path,
+        ":tls_fingerprint": tls_fingerprint,
+        ":name": server.name,
+        ":ip": server.ip
+    })?;
+    println!("Pinned certificate for {}", server.name);
+
+    Ok(())
+}
+
+// Add or update every server in an inventory file. Rows that fail any check
+// are reported and left out, the rest go in one transaction. Keys to prompt
+// for are only asked for once everything else about the file checks out.
+pub fn run_server_import(args: ServerImport, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Some(f) => f,
+        None => InventoryFormat::from_path(&args.file)?
+    };
+    let (rows, mut skipped) = read_inventory(&args.file, format)?;
+    let total = rows.len() + skipped.len();
+    let mut db = database::open(paths, config)?;
+    let vault = secrets::unlock(&db)?;
+
+    let mut ready = Vec::new();
+    for InventoryEntry { row, server: r, key } in rows {
+        let checked = check_ip_unchanged(&db, config, r.name.trim(), r.ip.trim())
+            .map_err(|e| e.to_string())
+            .and_then(|_| key.resolve());
+        match checked {
+            Ok(apikey) => ready.push((row, r, apikey)),
+            Err(reason) => skipped.push(SkippedRow { row, name: r.name, reason })
+        }
+    }
+
+    let mut servers = Vec::new();
+    for (row, r, apikey) in ready {
+        let apikey = match apikey {
+            Some(k) => k,
+            None => match KeySource::Prompt.read(&format!("API Key for {}: ", r.name.trim())) {
+                Ok(k) => k,
+                Err(e) => {
+                    skipped.push(SkippedRow { row, name: r.name, reason: e.to_string() });
+                    continue
+                }
+            }
+        };
+        servers.push(ServerRow {
+            hostname: r.hostname(),
+            group: r.group(),
+            ..ServerRow::new(r.name.trim().to_string(), r.ip.trim().to_string(), r.user.trim().to_string(), apikey)
+        });
+    }
+
+    let tx = db.transaction()?;
+    for server in &servers {
+        match ServerRow::select_by_name(&tx, config, &server.name) {
+            Ok(existing) => update_imported(&tx, config, existing, server, vault.as_ref())?,
+            Err(_) => upsert_server(&tx, config, server, vault.as_ref())?
+        }
+    }
+    tx.commit()?;
+    println!("Imported {} of {} servers", servers.len(), total);
+
+    if skipped.is_empty() {
+        return Ok(())
+    }
+    skipped.sort_by_key(|s| s.row);
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["row", "name", "skipped because"]);
+    for s in &skipped {
+        builder.push_record([s.row.to_string(), s.name.clone(), s.reason.clone()]);
+    }
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+    println!("{}", table);
+
+    Err(format!("{} of {} rows skipped", skipped.len(), total).into())
+}
+
+// Change a server in place. The primary key is (name, ip) and the domains
+// table points at it, so a rename or new IP moves the server's domains and
+// history along with it in the same transaction.
+pub fn run_server_edit(args: ServerEdit, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.name)?;
+
+    let name = args.rename.unwrap_or(server.name.clone());
+    if name != server.name && ServerRow::select_by_name(&db, config, &name).is_ok() {
+        return Err(format!("There is already a server named {}", name).into())
+    }
+    let ip = args.ip.unwrap_or(server.ip.clone());
+    let cleared = |new: Option<String>, old: Option<String>| match new {
+        Some(v) if v.trim().is_empty() => "NULL".to_string(),
+        Some(v) => v,
+        None => old.unwrap_or("NULL".to_string())
+    };
+    let hostname = cleared(args.hostname, server.hostname.clone());
+    let group = cleared(args.group, server.group.clone());
+    let source = match args.apikey {
+        true => Some(KeySource::Prompt),
+        false => args.key.source()
+    };
+    let apikey = match source {
+        Some(source) => Some(Vault::seal(secrets::unlock(&db)?.as_ref(), &source.read("API Key: ")?)?),
+        None => None
+    };
+
+    let tx = db.transaction()?;
+    tx.pragma_update(None, "defer_foreign_keys", true)?;
+    let removed = tx.execute(&SERVER_DELETE_DUPLICATES(config), rusqlite::named_params! {
+        ":name": server.name,
+        ":ip": server.ip
+    })?;
+    tx.execute(&SERVER_UPDATE(config), rusqlite::named_params! {
+        ":name": name,
+        ":ip": ip,
+        ":user": args.user.unwrap_or(server.user.clone()),
+        ":hostname": hostname,
+        ":group": group,
+        ":apikey": apikey,
+        ":old_name": server.name,
+        ":old_ip": server.ip
+    })?;
+    tx.execute(&DOMAIN_MOVE_SERVER(config), rusqlite::named_params! {
+        ":name": name,
+        ":ip": ip,
+        ":old_name": server.name
+    })?;
+    tx.execute(&HISTORY_RENAME_SERVER(), rusqlite::named_params! {
+        ":name": name,
+        ":old_name": server.name
+    })?;
+    tx.execute(&FACTS_RENAME_SERVER(), rusqlite::named_params! {
+        ":name": name,
+        ":old_name": server.name
+    })?;
+    tx.execute(&TAGS_RENAME_SERVER(), rusqlite::named_params! {
+        ":name": name,
+        ":old_name": server.name
+    })?;
+    tx.commit()?;
+
+    ResponseCache::open(paths).rename_server(&server.name, &name)?;
+    if removed > 0 {
+        println!("Dropped {} stale rows for {} under other IPs", removed, server.name);
+    }
+    println!("Updated {}", name);
+
+    Ok(())
+}
+
+// Drop a server along with everything stored about it. The database side
+// happens in one transaction, cached responses go once that has committed.
+pub fn run_server_remove(args: ServerRemove, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.name)?;
+    let domains = DomainRow::select_by_server(&db, config, &server.name)?.len();
+
+    let history = if args.keep_history { "" } else { " and its change history" };
+    if !args.yes && !confirm(&format!("Remove {} ({}), its {} domains{}?", server.name, server.ip, domains, history))? {
+        return Err("Server not removed".into())
+    }
+
+    let tx = db.transaction()?;
+    tx.execute(&DOMAIN_DELETE_BY_SERVER(config), rusqlite::named_params! { ":server_name": server.name })?;
+    if !args.keep_history {
+        tx.execute(&HISTORY_DELETE_BY_SERVER(), rusqlite::named_params! { ":server_name": server.name })?;
+    }
+    tx.execute(&FACTS_DELETE_BY_SERVER(), rusqlite::named_params! { ":server_name": server.name })?;
+    tx.execute(&TAGS_DELETE_BY_SERVER(), rusqlite::named_params! { ":server_name": server.name })?;
+    tx.execute(&SERVER_DELETE(config), rusqlite::named_params! { ":name": server.name })?;
+    tx.commit()?;
+
+    ResponseCache::open(paths).remove_server(&server.name)?;
+    println!("Removed {}", server.name);
+
+    Ok(())
+}
+
+// Same fan out as server test. Facts that came back are stored, servers that
+// didn't answer keep what we had for them.
+async fn collect_facts(db: &Connection, config: &Config, servers: Vec<ServerRow>) -> Result<usize, Box<dyn Error>> {
+    let collected = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let limit = Arc::new(Semaphore::new(config.sync_jobs().max(1)));
+    let mut tasks = JoinSet::new();
+    for server in servers {
+        let limit = limit.clone();
+        let client = WhmClient::new(&server, config);
+        tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await;
+            match client {
+                Ok(client) => (server.name, ServerFacts::collect(&client, collected).await),
+                Err(e) => (server.name, Err(e))
+            }
+        });
+    }
+
+    let mut failed = 0;
+    for (name, facts) in tasks.join_all().await {
+        match facts {
+            Ok(f) => f.upsert(db)?,
+            Err(e) => {
+                eprintln!("{}: unable to collect facts. {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+// What the servers last told us about themselves, optionally asking them
+// again first
+pub async fn run_server_facts(args: ServerFactsArgs, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let mut servers = args.select.select(&db, config)?;
+    let names: HashSet<String> = servers.iter().map(|s| s.name.clone()).collect();
+
+    let mut failed = 0;
+    if args.refresh {
+        secrets::open_keys(&db, &mut servers)?;
+        failed = collect_facts(&db, config, servers).await?;
+    }
+
+    let facts: Vec<(ServerFacts, String)> = ServerFacts::select_all(&db)?
+        .into_iter()
+        .filter(|(f, _)| names.contains(&f.server))
+        .filter(|(f, _)| args.version.as_ref().is_none_or(|v| version_matches(&f.version, v)))
+        .filter(|(f, _)| args.older_than.as_ref().is_none_or(|v| version_older_than(&f.version, v)))
+        .collect();
+
+    match args.output {
+        OutputFormat::Table => {
+            let mut builder = tabled::builder::Builder::new();
+            builder.push_record(["server", "version", "tier", "os", "hostname", "load", "accounts", "fullest disk",
+                "collected"]);
+            for (f, collected) in &facts {
+                let load = match (f.load_1, f.load_5, f.load_15) {
+                    (Some(a), Some(b), Some(c)) => format!("{:.2} {:.2} {:.2}", a, b, c),
+                    _ => String::new()
+                };
+                let disk = f.fullest_disk().map(|d| format!("{} {:.0}%", d.mount, d.percentage)).unwrap_or_default();
+                builder.push_record([
+                    f.server.clone(),
+                    f.version.clone(),
+                    f.tier.clone().unwrap_or_default(),
+                    f.os.clone().unwrap_or_default(),
+                    f.hostname.clone().unwrap_or_default(),
+                    load,
+                    f.accounts.map(|a| a.to_string()).unwrap_or_default(),
+                    disk,
+                    collected.clone()
+                ]);
+            }
+
+            let mut table = builder.build();
+            table.with(tabled::settings::Style::rounded());
+            println!("{}", table);
+        }
+        OutputFormat::Json => {
+            let facts: Vec<&ServerFacts> = facts.iter().map(|(f, _)| f).collect();
+            println!("{}", serde_json::to_string_pretty(&facts)?);
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for (f, collected) in facts {
+                let disk = f.fullest_disk().cloned();
+                writer.serialize(FactsListing {
+                    server: f.server,
+                    version: f.version,
+                    tier: f.tier,
+                    os: f.os,
+                    hostname: f.hostname,
+                    load_1: f.load_1,
+                    load_5: f.load_5,
+                    load_15: f.load_15,
+                    accounts: f.accounts,
+                    fullest_disk: disk.as_ref().map(|d| d.mount.clone()),
+                    fullest_disk_percentage: disk.map(|d| d.percentage),
+                    collected
+                })?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("Unable to collect facts from {} servers", failed).into())
+    }
+
+    Ok(())
+}
+
+pub fn run_server_tag_add(args: ServerTagAdd, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.name)?;
+
+    let tx = db.transaction()?;
+    for (key, value) in &args.tags {
+        tx.execute(&TAGS_UPSERT(), rusqlite::named_params! {
+            ":server_name": server.name,
+            ":key": key,
+            ":value": value
+        })?;
+    }
+    tx.commit()?;
+    println!("Tagged {} with {}", server.name,
+        args.tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(","));
+
+    Ok(())
+}
+
+pub fn run_server_tag_remove(args: ServerTagRemove, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut db = database::open(paths, config)?;
+    let server = ServerRow::select_by_name(&db, config, &args.name)?;
+
+    let tx = db.transaction()?;
+    let mut removed = 0;
+    for tag in &args.tags {
+        let (key, value) = match tag.split_once('=') {
+            Some((k, v)) => (k.trim(), Some(v.trim())),
+            None => (tag.trim(), None)
+        };
+        removed += tx.execute(&TAGS_DELETE(), rusqlite::named_params! {
+            ":server_name": server.name,
+            ":key": key,
+            ":value": value
+        })?;
+    }
+    tx.commit()?;
+    println!("Removed {} tags from {}", removed, server.name);
+
+    Ok(())
+}
+
+pub fn run_server_tag_list(args: ServerTagList, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let tags = tags_by_server(&db)?;
+    let mut servers = args.select.select(&db, config)?;
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["server", "tags"]);
+    for s in servers {
+        let mut t: Vec<String> = tags.get(&s.name).into_iter().flatten()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        t.sort();
+        builder.push_record([s.name, t.join(",")]);
+    }
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+    println!("{}", table);
+
+    Ok(())
+}
+
+// Where a server is in its token rotation. See the migration that added
+// these columns.
+struct TokenState {
+    pending_apikey: Option<String>,
+    pending_token_name: Option<String>,
+    stale_token_name: Option<String>
+}
+
+impl TokenState {
+    fn select(db: &Connection, config: &Config, server: &ServerRow) -> Result<Self, Box<dyn Error>> {
+        Ok(db.query_row(&TOKEN_STATE_SELECT(config), rusqlite::named_params! {
+            ":name": server.name,
+            ":ip": server.ip
+        }, |r| Ok(TokenState {
+            pending_apikey: r.get(0)?,
+            pending_token_name: r.get(1)?,
+            stale_token_name: r.get(2)?
+        }))?)
+    }
+}
+
+// Replace each server's API token with a new one holding the same ACLs.
+// Servers go one at a time, and every step is written down before the next
+// one starts, so running it again after an interruption picks up where it
+// stopped instead of creating yet another token.
+pub async fn run_server_rotate_token(args: ServerRotateToken, paths: &GlobalPaths, config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = database::open(paths, config)?;
+    let mut servers = match &args.name {
+        Some(name) => vec![ServerRow::select_by_name(&db, config, name)?],
+        None => args.select.select(&db, config)?
+    };
+    // Unlocked once for both reading the current keys and sealing new ones
+    let vault = secrets::unlock(&db)?;
+    secrets::decrypt_keys(vault.as_ref(), &mut servers)?;
+
+    let mut results = Vec::new();
+    for server in servers {
+        let result = rotate_token(&db, config, vault.as_ref(), &server, args.old_token_name.as_deref()).await;
+        if let Err(e) = &result {
+            log::error!("{}: token rotation failed. {}", server.name, e);
+        }
+        results.push((server.name, result));
+    }
+
+    let mut builder = tabled::builder::Builder::new();
+    builder.push_record(["server", "status", "detail"]);
+    for (name, result) in &results {
+        let (status, detail) = match result {
+            Ok(detail) => ("ok".to_string(), detail.clone()),
+            Err(e) => ("failed".to_string(), e.to_string())
+        };
+        builder.push_record([name.clone(), status, detail]);
+    }
+    let mut table = builder.build();
+    table.with(tabled::settings::Style::rounded());
+    println!("{}", table);
+
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} servers failed", failed, results.len()).into())
+    }
+
+    Ok(())
+}
+
+// Create, verify, store, revoke. Returns what happened for the report.
+async fn rotate_token(db: &Connection, config: &Config, vault: Option<&Vault>, server: &ServerRow,
+    old_token_name: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let where_ = rusqlite::named_params! { ":name": server.name, ":ip": server.ip };
+    let state = TokenState::select(db, config, server)?;
+    let mut current = server.apikey.clone();
+    let mut notes = Vec::new();
+
+    // A stale token means the new one is already in use and only the
+    // revoke is left
+    let stale = match state.stale_token_name {
+        Some(stale) => Some(stale),
+        None => {
+            let old = WhmClient::new(server, config)?;
+            let acls = old.myprivs().await?;
+            let (key, name) = match (state.pending_apikey, state.pending_token_name) {
+                (Some(key), Some(name)) => {
+                    notes.push(format!("resumed {}", name));
+                    let key = match vault {
+                        Some(v) if secrets::is_encrypted(&key) => v.decrypt(&key)?,
+                        _ => key
+                    };
+                    (key, name)
+                },
+                (pending_key, pending_name) => {
+                    // A name without a key is a create that never came back.
+                    // The token may exist anyway and there's no way to get
+                    // its secret again, so it's revoked before starting over.
+                    if let (None, Some(orphan)) = (pending_key, pending_name) {
+                        if old.api_token_list().await?.contains(&orphan) {
+                            old.api_token_revoke(&orphan).await?;
+                            notes.push(format!("revoked unfinished {}", orphan));
+                        }
+                        db.execute(&TOKEN_CLEAR_PENDING(config), where_)?;
+                    }
+
+                    let name = format!("cpcm-{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+                    let set_pending = |key: Option<String>| db.execute(&TOKEN_SET_PENDING(config), rusqlite::named_params! {
+                        ":apikey": key,
+                        ":token_name": name,
+                        ":name": server.name,
+                        ":ip": server.ip
+                    });
+                    // The name is written down first so an interrupted create
+                    // can be found and cleaned up next time
+                    set_pending(None)?;
+                    let key = old.api_token_create(&name, &acls).await?;
+                    set_pending(Some(Vault::seal(vault, &key)?))?;
+                    (key, name)
+                }
+            };
+
+            // The new token has to answer and hold the same ACLs before
+            // anything depends on it. If it doesn't, get rid of it.
+            let new = WhmClient::new(&ServerRow { apikey: key.clone(), ..server.clone() }, config)?;
+            let verified = match new.myprivs().await {
+                Ok(mut new_acls) => {
+                    let mut old_acls = acls.clone();
+                    old_acls.sort();
+                    new_acls.sort();
+                    match new_acls == old_acls {
+                        true => Ok(()),
+                        false => Err(format!("new token {} has ACLs {} instead of {}",
+                            name, new_acls.join(", "), old_acls.join(", ")))
+                    }
+                },
+                Err(e) => Err(format!("new token {} doesn't work: {}", name, e))
+            };
+            if let Err(e) = verified {
+                if let Err(revoke) = old.api_token_revoke(&name).await {
+                    log::warn!("{}: unable to revoke {}. {}", server.name, name, revoke);
+                }
+                db.execute(&TOKEN_CLEAR_PENDING(config), where_)?;
+                return Err(e.into())
+            }
+
+            db.execute(&TOKEN_PROMOTE(config), rusqlite::named_params! {
+                ":old_token_name": old_token_name,
+                ":name": server.name,
+                ":ip": server.ip
+            })?;
+            current = key;
+            notes.push(format!("now using {}", name));
+            TokenState::select(db, config, server)?.stale_token_name
+        }
+    };
+
+    match stale {
+        Some(stale) => {
+            let client = WhmClient::new(&ServerRow { apikey: current, ..server.clone() }, config)?;
+            // Already gone if an earlier run revoked it and stopped short of
+            // writing that down
+            if client.api_token_list().await?.contains(&stale) {
+                client.api_token_revoke(&stale).await?;
+            }
+            db.execute(&TOKEN_CLEAR_STALE(config), where_)?;
+            notes.push(format!("revoked {}", stale));
+        },
+        None => notes.push("old token not revoked, its name is unknown. Pass --old-token-name".to_string())
+    }
+
+    Ok(notes.join(", "))
+}
+
+// [y/N] question on stdout, answer from stdin
+fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    println!("{} [y/N] ", question);
+    let mut buf = String::new();
+    io::stdin().read_line(&mut buf)?;
+
+    Ok(matches!(buf.trim(), "Yes" | "Y" | "y"))
+}
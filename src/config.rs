@@ -2,11 +2,38 @@ use std::{error::Error, io::Read, fs};
 use serde::{Serialize, Deserialize};
 use crate::global_paths::GlobalPaths;
 
+const DEFAULT_SYNC_JOBS: usize = 8;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 500;
+const DEFAULT_REQUESTS_PER_SECOND: f64 = 5.0;
+const DEFAULT_CACHE_KEEP: usize = 10;
+// Past this the backoff is capped anyway and a sync just hangs on one server
+pub const MAX_RETRIES_LIMIT: u32 = 10;
+// Slowest rate allowed other than 0, one request a day
+pub const MIN_REQUESTS_PER_SECOND: f64 = 1.0 / 86400.0;
+
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub tabname_domain: Option<String>,
-    pub tabname_server: Option<String>
+    pub tabname_server: Option<String>,
+    // How many servers domain sync talks to at once
+    pub sync_jobs: Option<usize>,
+    // Defaults for every WHM call. Each can be overridden per server.
+    pub connect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    // 0 means no limit
+    pub requests_per_second: Option<f64>,
+    // Keep raw WHM responses under the data directory for debug replay
+    pub cache_responses: Option<bool>,
+    // How many responses to keep per server and call
+    pub cache_keep: Option<usize>,
+    // Refresh server facts as part of every domain sync
+    pub collect_facts: Option<bool>
 }
 
 impl Config {
@@ -26,6 +53,42 @@ impl Config {
             Some(s) => Some(s),
             None => Some("servers".to_string())
         };
+        config.sync_jobs = match config.sync_jobs {
+            Some(j) => Some(j),
+            None => Some(DEFAULT_SYNC_JOBS)
+        };
+        config.connect_timeout_secs = match config.connect_timeout_secs {
+            Some(t) => Some(t),
+            None => Some(DEFAULT_CONNECT_TIMEOUT_SECS)
+        };
+        config.read_timeout_secs = match config.read_timeout_secs {
+            Some(t) => Some(t),
+            None => Some(DEFAULT_READ_TIMEOUT_SECS)
+        };
+        config.max_retries = match config.max_retries {
+            Some(r) => Some(check_max_retries(r).map_err(|e| format!("max_retries in config: {}", e))?),
+            None => Some(DEFAULT_MAX_RETRIES)
+        };
+        config.retry_backoff_ms = match config.retry_backoff_ms {
+            Some(b) => Some(b),
+            None => Some(DEFAULT_RETRY_BACKOFF_MS)
+        };
+        config.requests_per_second = match config.requests_per_second {
+            Some(r) => Some(check_requests_per_second(r).map_err(|e| format!("requests_per_second in config: {}", e))?),
+            None => Some(DEFAULT_REQUESTS_PER_SECOND)
+        };
+        config.cache_responses = match config.cache_responses {
+            Some(c) => Some(c),
+            None => Some(false)
+        };
+        config.cache_keep = match config.cache_keep {
+            Some(k) => Some(k),
+            None => Some(DEFAULT_CACHE_KEEP)
+        };
+        config.collect_facts = match config.collect_facts {
+            Some(c) => Some(c),
+            None => Some(false)
+        };
 
         Ok(config)
     }
@@ -40,6 +103,43 @@ impl Config {
         // i just realized moands sounds like gonads
         self.tabname_server.as_ref().unwrap()
     }
+
+    pub fn sync_jobs(&self) -> usize {
+        self.sync_jobs.unwrap()
+    }
+
+    pub fn connect_timeout_secs(&self) -> u64 {
+        self.connect_timeout_secs.unwrap()
+    }
+
+    pub fn read_timeout_secs(&self) -> u64 {
+        self.read_timeout_secs.unwrap()
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap()
+    }
+
+    pub fn retry_backoff_ms(&self) -> u64 {
+        self.retry_backoff_ms.unwrap()
+    }
+
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second.unwrap()
+    }
+
+    pub fn cache_responses(&self) -> bool {
+        self.cache_responses.unwrap()
+    }
+
+    pub fn cache_keep(&self) -> usize {
+        self.cache_keep.unwrap()
+    }
+
+    pub fn collect_facts(&self) -> bool {
+        self.collect_facts.unwrap()
+    }
+
     pub fn write_file(&self, paths: &GlobalPaths) -> Result<(), Box<dyn Error>> {
         let json_data = serde_json::to_string(self)?;
         fs::write(paths.configfile(), json_data)?;
@@ -47,12 +147,46 @@ impl Config {
         Ok(())
     }
 
+}
 
-    pub fn default() -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Self {
             tabname_domain: Some("domains".to_string()),
-            tabname_server: Some("servers".to_string())
+            tabname_server: Some("servers".to_string()),
+            sync_jobs: Some(DEFAULT_SYNC_JOBS),
+            connect_timeout_secs: Some(DEFAULT_CONNECT_TIMEOUT_SECS),
+            read_timeout_secs: Some(DEFAULT_READ_TIMEOUT_SECS),
+            max_retries: Some(DEFAULT_MAX_RETRIES),
+            retry_backoff_ms: Some(DEFAULT_RETRY_BACKOFF_MS),
+            requests_per_second: Some(DEFAULT_REQUESTS_PER_SECOND),
+            cache_responses: Some(false),
+            cache_keep: Some(DEFAULT_CACHE_KEEP),
+            collect_facts: Some(false)
         }
     }
+}
+
+pub fn check_max_retries(retries: u32) -> Result<u32, String> {
+    match retries <= MAX_RETRIES_LIMIT {
+        true => Ok(retries),
+        false => Err(format!("{} is more than the limit of {}", retries, MAX_RETRIES_LIMIT))
+    }
+}
+
+// 0 turns the limit off, anything else has to be a real rate
+pub fn check_requests_per_second(rps: f64) -> Result<f64, String> {
+    match rps == 0.0 || (rps.is_finite() && rps >= MIN_REQUESTS_PER_SECOND) {
+        true => Ok(rps),
+        false => Err(format!("{} isn't 0 or a rate of at least {:.6} per second", rps, MIN_REQUESTS_PER_SECOND))
+    }
+}
+
+// clap parsers for the per-server overrides
+pub fn parse_max_retries(s: &str) -> Result<u32, String> {
+    check_max_retries(s.parse().map_err(|e| format!("{}", e))?)
+}
 
+pub fn parse_requests_per_second(s: &str) -> Result<f64, String> {
+    check_requests_per_second(s.parse().map_err(|e| format!("{}", e))?)
 }
@@ -0,0 +1,34 @@
+use std::error::Error;
+use rusqlite::Connection;
+
+use crate::global_paths::GlobalPaths;
+use crate::config::Config;
+use crate::sql_strings::SQLMIGRATIONS;
+
+// Open the database and bring the schema up to date. Everything other than
+// init should get its connection from here rather than Connection::open.
+// Foreign keys are off by default in SQLite and have to be asked for on
+// every connection.
+pub fn open(paths: &GlobalPaths, config: &Config) -> Result<Connection, Box<dyn Error>> {
+    let mut db = Connection::open(paths.dbfile())?;
+    db.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut db, config)?;
+
+    Ok(db)
+}
+
+// PRAGMA user_version holds the number of SQLMIGRATIONS already applied.
+// Each migration runs in its own transaction together with the version bump.
+pub fn migrate(db: &mut Connection, config: &Config) -> Result<(), Box<dyn Error>> {
+    let version: usize = db.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+
+    for (i, migration) in SQLMIGRATIONS(config).iter().enumerate().skip(version) {
+        log::debug!("Applying migration {}", i + 1);
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use rusqlite::Connection;
+use serde_json::{Map, Value};
+
+use crate::sqlite_types::DomainRow;
+use crate::sql_strings::HISTORY_INSERT;
+
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String
+}
+
+#[derive(Debug, Clone)]
+pub struct DomainChange {
+    pub domain: String,
+    pub fields: Vec<FieldChange>
+}
+
+// Difference between what the domains table has for one server and what
+// that server just reported. Both sides are expected to be safe_unwrap'd.
+#[derive(Debug, Clone)]
+pub struct DomainDiff {
+    pub server: String,
+    pub added: Vec<DomainRow>,
+    pub removed: Vec<DomainRow>,
+    pub changed: Vec<DomainChange>
+}
+
+impl DomainDiff {
+    pub fn new(server: &str, old: Vec<DomainRow>, new: Vec<DomainRow>) -> Self {
+        let key = |r: DomainRow| (r.domain.clone().unwrap_or_default(), r);
+        let mut old: BTreeMap<String, DomainRow> = old.into_iter().map(key).collect();
+        let new: BTreeMap<String, DomainRow> = new.into_iter().map(key).collect();
+
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for (domain, new_row) in new {
+            match old.remove(&domain) {
+                None => added.push(new_row),
+                Some(old_row) => {
+                    let fields: Vec<FieldChange> = old_row.fields().into_iter()
+                        .zip(new_row.fields())
+                        .filter(|((_, o), (_, n))| o != n)
+                        .map(|((field, old), (_, new))| FieldChange { field, old, new })
+                        .collect();
+                    if !fields.is_empty() {
+                        changed.push(DomainChange { domain, fields });
+                    }
+                }
+            }
+        }
+
+        Self {
+            server: server.to_string(),
+            added,
+            removed: old.into_values().collect(),
+            changed
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // Write one domain_history row per added, removed and changed domain
+    pub fn record_history(&self, db: &Connection, timestamp: i64) -> Result<(), Box<dyn Error>> {
+        let mut stmt = db.prepare(&HISTORY_INSERT())?;
+        let as_json = |fields: Vec<(&'static str, String)>| Value::Object(
+            fields.into_iter().map(|(k, v)| (k.to_string(), Value::String(v))).collect::<Map<_, _>>()
+        ).to_string();
+
+        let mut insert = |domain: &str, action: &str, old: Option<String>, new: Option<String>| {
+            stmt.execute(rusqlite::named_params! {
+                ":timestamp": timestamp,
+                ":server_name": self.server,
+                ":domain": domain,
+                ":action": action,
+                ":old_values": old,
+                ":new_values": new
+            })
+        };
+
+        for row in &self.added {
+            insert(row.domain.as_deref().unwrap_or("NULL"), "insert", None, Some(as_json(row.fields())))?;
+        }
+        for change in &self.changed {
+            let old = change.fields.iter().map(|f| (f.field, f.old.clone())).collect();
+            let new = change.fields.iter().map(|f| (f.field, f.new.clone())).collect();
+            insert(&change.domain, "update", Some(as_json(old)), Some(as_json(new)))?;
+        }
+        for row in &self.removed {
+            insert(row.domain.as_deref().unwrap_or("NULL"), "delete", Some(as_json(row.fields())), None)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        println!("{}: {} added, {} removed, {} changed",
+            self.server, self.added.len(), self.removed.len(), self.changed.len());
+
+        for row in &self.added {
+            println!("  + {}", row.domain.as_deref().unwrap_or("NULL"));
+        }
+        for row in &self.removed {
+            println!("  - {}", row.domain.as_deref().unwrap_or("NULL"));
+        }
+        for change in &self.changed {
+            println!("  ~ {}", change.domain);
+            for f in &change.fields {
+                println!("      {}: {} -> {}", f.field, f.old, f.new);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{error::Error, fmt, io::ErrorKind};
 use crate::global_paths::CfgPath; 
 
 #[derive(Debug, Clone)]
@@ -14,3 +14,98 @@ impl fmt::Display for PathsError<'_> {
         write!(f, "")
     }
 }
+
+// Errors returned by WhmClient. Api covers calls that made it to WHM but came
+// back with metadata.result == 0, the reason is whatever WHM told us. Config
+// is a servers row that can't be turned into a request at all.
+#[derive(Debug)]
+pub enum WhmError {
+    Request(reqwest::Error),
+    Status(reqwest::StatusCode),
+    Decode(String),
+    Tls(String),
+    Transport(String),
+    Config(String),
+    Api { function: String, reason: String }
+}
+
+impl fmt::Display for WhmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // reqwest's own message stops at "error sending request", the
+            // reason is at the bottom of the chain
+            WhmError::Request(e) => match self.causes().last() {
+                Some(cause) => write!(f, "Request failed: {}: {}", e, cause),
+                None => write!(f, "Request failed: {}", e)
+            },
+            WhmError::Status(s) => write!(f, "WHM returned HTTP {}", s),
+            WhmError::Decode(why) => write!(f, "Unable to decode WHM response: {}", why),
+            WhmError::Tls(why) => write!(f, "TLS setup failed: {}", why),
+            WhmError::Transport(why) => write!(f, "Unable to set up transport: {}", why),
+            WhmError::Config(why) => write!(f, "Server is misconfigured: {}", why),
+            WhmError::Api { function, reason } => write!(f, "{} failed: {}", function, reason)
+        }
+    }
+}
+
+impl Error for WhmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WhmError::Request(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl WhmError {
+    // Every error under this one. io::Error::source() skips over the error
+    // it wraps, and hyper nests those, so io errors are followed through
+    // get_ref() instead.
+    fn causes(&self) -> impl Iterator<Item = &(dyn Error + 'static)> {
+        std::iter::successors(self.source(), |&e| {
+            match e.downcast_ref::<std::io::Error>().and_then(|io| io.get_ref()) {
+                Some(inner) => Some(inner as &(dyn Error + 'static)),
+                None => e.source()
+            }
+        })
+    }
+
+    // A rustls error anywhere under this one. The handshake fails inside
+    // the connector, which hands it up wrapped in io errors.
+    pub fn is_tls(&self) -> bool {
+        if let WhmError::Tls(_) = self {
+            return true
+        }
+        self.causes().any(|e| e.is::<rustls::Error>())
+    }
+
+    // 5xx, refused connections and connections dropped halfway through
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WhmError::Status(s) => s.is_server_error(),
+            WhmError::Request(e) if e.is_connect() => !e.is_timeout() && !self.is_tls(),
+            WhmError::Request(_) => self.causes()
+                .filter_map(|e| e.downcast_ref::<std::io::Error>())
+                .any(|io| matches!(io.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe)),
+            _ => false
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, WhmError::Request(e) if e.is_timeout())
+    }
+
+    pub fn is_auth(&self) -> bool {
+        match self {
+            WhmError::Status(s) => *s == reqwest::StatusCode::UNAUTHORIZED || *s == reqwest::StatusCode::FORBIDDEN,
+            WhmError::Api { reason, .. } => reason.to_lowercase().contains("access denied"),
+            _ => false
+        }
+    }
+}
+
+impl From<reqwest::Error> for WhmError {
+    fn from(e: reqwest::Error) -> Self {
+        WhmError::Request(e)
+    }
+}
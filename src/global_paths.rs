@@ -29,7 +29,7 @@ impl GlobalPaths {
         [&self.cpcmdatadir, &self.cpcmdbfile, &self.cpcmconfig]
     } 
 
-    pub fn checkpaths(&self) -> Result<(), error_types::PathsError> {
+    pub fn checkpaths(&self) -> Result<(), error_types::PathsError<'_>> {
         let missingpaths: Vec<&CfgPath> = self.as_array().into_iter()
             .filter(|&x| !x.path.exists())
             .collect();
@@ -37,7 +37,7 @@ impl GlobalPaths {
         if missingpaths.is_empty() {
             Ok(())
         } else {
-            Err(PathsError{ missingpaths: missingpaths })
+            Err(PathsError{ missingpaths })
         }
 
 
@@ -84,6 +84,10 @@ impl GlobalPaths {
     pub fn datadir(&self) -> &PathBuf {
         &self.cpcmdatadir.path
     }
+
+    pub fn responsedir(&self) -> PathBuf {
+        self.cpcmdatadir.path.join("responses")
+    }
 }
 
 fn get_cpcm_datadir() -> Result<PathBuf, Box<dyn Error>> {
@@ -92,7 +96,7 @@ fn get_cpcm_datadir() -> Result<PathBuf, Box<dyn Error>> {
             let datadir = datadir.trim();
 
             if datadir.is_empty() {
-                return get_cpcm_default_datadir()
+                get_cpcm_default_datadir()
             } else {
                 let rval = PathBuf::from(datadir);
                 if rval.is_relative() {
@@ -114,14 +118,14 @@ fn get_cpcm_datadir() -> Result<PathBuf, Box<dyn Error>> {
 
 fn get_cpcm_default_datadir() -> Result<PathBuf, Box<dyn Error>> {
     let path = dirs::home_dir()
-        .ok_or_else(|| format!("Unable to find user's home directory"))?
+        .ok_or("Unable to find user's home directory")?
         .join(".cpcm");
 
     if !path.is_absolute() {
-        return Err(format!("System did not return a valid home directory path {}", path.display())
+        Err(format!("System did not return a valid home directory path {}", path.display())
             .into())
     } else {
-        return Ok(path)
+        Ok(path)
     }
 }
 
@@ -1,10 +1,24 @@
 pub mod command_domain;
 pub mod command_server;
 pub mod command_init;
+pub mod command_history;
+pub mod command_debug;
+pub mod command_secrets;
 
 pub mod cli;
+pub mod apikey;
+pub mod secrets;
 pub mod config;
 pub mod global_paths;
 pub mod error_types;
 pub mod sqlite_types;
 pub mod sql_strings;
+pub mod whm_client;
+pub mod database;
+pub mod tls;
+pub mod transport;
+pub mod server_select;
+pub mod server_inventory;
+pub mod domain_diff;
+pub mod response_cache;
+pub mod server_facts;
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::config::Config;
+use crate::global_paths::GlobalPaths;
+
+// Raw whmapi1 responses as WHM sent them, gzipped, one file per call:
+// <datadir>/responses/<server>/<function>/<unix millis>.json.gz
+// Only the newest `keep` responses per server and function are kept.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    keep: usize
+}
+
+// One stored response
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub path: PathBuf,
+    // Unix time in milliseconds
+    pub saved: i64
+}
+
+impl ResponseCache {
+    pub fn open(paths: &GlobalPaths) -> Self {
+        Self { dir: paths.responsedir(), keep: 0 }
+    }
+
+    // None unless cache_responses is turned on in the config
+    pub fn from_config(paths: &GlobalPaths, config: &Config) -> Option<Self> {
+        if !config.cache_responses() {
+            return None
+        }
+
+        Some(Self { dir: paths.responsedir(), keep: config.cache_keep() })
+    }
+
+    fn call_dir(&self, server: &str, function: &str) -> PathBuf {
+        self.dir.join(safe_name(server)).join(safe_name(function))
+    }
+
+    pub fn store(&self, server: &str, function: &str, body: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+        let dir = self.call_dir(server, function);
+        fs::create_dir_all(&dir)?;
+
+        let saved = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = dir.join(format!("{}.json.gz", saved));
+        let mut gz = GzEncoder::new(fs::File::create(&path)?, Compression::default());
+        gz.write_all(body)?;
+        gz.finish()?;
+
+        for old in self.list(server, function)?.into_iter().rev().skip(self.keep.max(1)) {
+            log::debug!("Pruning cached response {}", old.path.display());
+            fs::remove_file(&old.path)?;
+        }
+
+        Ok(path)
+    }
+
+    // Oldest first
+    pub fn list(&self, server: &str, function: &str) -> Result<Vec<CachedResponse>, Box<dyn Error>> {
+        let dir = self.call_dir(server, function);
+        if !dir.exists() {
+            return Ok(Vec::new())
+        }
+
+        let mut responses: Vec<CachedResponse> = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let path = e.path();
+                let saved = path.file_name()?.to_str()?.strip_suffix(".json.gz")?.parse().ok()?;
+                Some(CachedResponse { path, saved })
+            })
+            .collect();
+        responses.sort_by_key(|r| r.saved);
+
+        Ok(responses)
+    }
+
+    pub fn latest(&self, server: &str, function: &str) -> Result<Option<CachedResponse>, Box<dyn Error>> {
+        Ok(self.list(server, function)?.pop())
+    }
+
+    pub fn rename_server(&self, old: &str, new: &str) -> Result<(), Box<dyn Error>> {
+        let (from, to) = (self.dir.join(safe_name(old)), self.dir.join(safe_name(new)));
+        if from == to || !from.exists() {
+            return Ok(())
+        }
+        // Whatever is there belonged to an earlier server by that name
+        if to.exists() {
+            fs::remove_dir_all(&to)?;
+        }
+        fs::rename(from, to)?;
+
+        Ok(())
+    }
+
+    // Everything stored for a server, e.g. when it's removed
+    pub fn remove_server(&self, server: &str) -> Result<(), Box<dyn Error>> {
+        let dir = self.dir.join(safe_name(server));
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CachedResponse {
+    pub fn read(&self) -> Result<String, Box<dyn Error>> {
+        let mut body = String::new();
+        GzDecoder::new(fs::File::open(&self.path)?).read_to_string(&mut body)
+            .map_err(|e| format!("Unable to read {}: {}", self.path.display(), e))?;
+
+        Ok(body)
+    }
+}
+
+// Server names come from the user, keep them to one harmless path component
+fn safe_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::sqlite_types::ServerRow;
+use crate::sql_strings::{SECRETS_SELECT, SECRETS_UPSERT};
+
+// Encrypted API keys are stored in the apikey column as
+// enc:v1:<base64 nonce>:<base64 ciphertext>, XChaCha20-Poly1305 under a key
+// derived from the passphrase with Argon2id. Anything without the prefix is
+// a plain key from before secrets migrate. The secrets table holds the salt,
+// the Argon2 parameters and `check`, a known value encrypted under the key
+// so a wrong passphrase is caught before it's used for anything.
+const PREFIX: &str = "enc:v1:";
+const CHECK: &str = "cpcm";
+
+pub const PASSPHRASE_ENV: &str = "CPCM_PASSPHRASE";
+pub const NEW_PASSPHRASE_ENV: &str = "CPCM_NEW_PASSPHRASE";
+
+// The derived key. Deliberately not Debug.
+#[derive(Clone)]
+pub struct Vault {
+    key: [u8; 32]
+}
+
+struct Meta {
+    salt: Vec<u8>,
+    params: Params,
+    check: String
+}
+
+// What secrets unlock leaves behind for later commands
+#[derive(Serialize, Deserialize)]
+struct CachedUnlock {
+    expires: i64,
+    key: String
+}
+
+impl Vault {
+    fn derive(passphrase: &str, salt: &[u8], params: Params) -> Result<Self, Box<dyn Error>> {
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| format!("Unable to derive key: {}", e))?;
+
+        Ok(Self { key })
+    }
+
+    pub fn encrypt(&self, plain: &str) -> Result<String, Box<dyn Error>> {
+        let mut nonce = [0u8; 24];
+        getrandom::getrandom(&mut nonce).map_err(|e| format!("Unable to get random bytes: {}", e))?;
+        let sealed = XChaCha20Poly1305::new(&self.key.into())
+            .encrypt(XNonce::from_slice(&nonce), plain.as_bytes())
+            .map_err(|_| "Unable to encrypt API key")?;
+
+        Ok(format!("{}{}:{}", PREFIX, BASE64.encode(nonce), BASE64.encode(sealed)))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, Box<dyn Error>> {
+        let (nonce, sealed) = stored.strip_prefix(PREFIX)
+            .and_then(|s| s.split_once(':'))
+            .ok_or("Not an encrypted API key")?;
+        let nonce = BASE64.decode(nonce)?;
+        if nonce.len() != 24 {
+            return Err("Encrypted API key has a bad nonce".into())
+        }
+        let plain = XChaCha20Poly1305::new(&self.key.into())
+            .decrypt(XNonce::from_slice(&nonce), BASE64.decode(sealed)?.as_slice())
+            .map_err(|_| "Unable to decrypt API key. Wrong passphrase?")?;
+
+        Ok(String::from_utf8(plain)?)
+    }
+
+    // Encrypt with the vault if there is one
+    pub fn seal(vault: Option<&Vault>, plain: &str) -> Result<String, Box<dyn Error>> {
+        match vault {
+            Some(v) => v.encrypt(plain),
+            None => Ok(plain.to_string())
+        }
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+fn load_meta(db: &Connection) -> Result<Option<Meta>, Box<dyn Error>> {
+    let mut stmt = db.prepare(&SECRETS_SELECT())?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let (Some(salt), Some(kdf), Some(check)) = (rows.get("salt"), rows.get("kdf"), rows.get("check")) else {
+        return Ok(None)
+    };
+    // m,t,p as given to Params::new
+    let kdf: Vec<u32> = kdf.split(',').map(|n| n.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|e| format!("Bad kdf parameters in secrets table: {}", e))?;
+    let [m, t, p] = kdf[..] else {
+        return Err("Bad kdf parameters in secrets table".into())
+    };
+
+    Ok(Some(Meta {
+        salt: BASE64.decode(salt)?,
+        params: Params::new(m, t, p, Some(32)).map_err(|e| format!("Bad kdf parameters in secrets table: {}", e))?,
+        check: check.clone()
+    }))
+}
+
+pub fn is_set_up(db: &Connection) -> Result<bool, Box<dyn Error>> {
+    Ok(load_meta(db)?.is_some())
+}
+
+// Start over with a fresh salt for this passphrase. Keys encrypted under the
+// old one are the caller's problem.
+pub fn create(db: &Connection, passphrase: &str) -> Result<Vault, Box<dyn Error>> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).map_err(|e| format!("Unable to get random bytes: {}", e))?;
+    let params = Params::default();
+    let kdf = format!("{},{},{}", params.m_cost(), params.t_cost(), params.p_cost());
+    let vault = Vault::derive(passphrase, &salt, params)?;
+
+    let mut stmt = db.prepare(&SECRETS_UPSERT())?;
+    for (name, value) in [("salt", BASE64.encode(salt)), ("kdf", kdf), ("check", vault.encrypt(CHECK)?)] {
+        stmt.execute(rusqlite::named_params! { ":name": name, ":value": value })?;
+    }
+
+    Ok(vault)
+}
+
+fn verify(meta: &Meta, vault: Vault) -> Result<Vault, Box<dyn Error>> {
+    match vault.decrypt(&meta.check) {
+        Ok(c) if c == CHECK => Ok(vault),
+        _ => Err("Wrong passphrase".into())
+    }
+}
+
+// The vault for this database, or None if secrets migrate was never run.
+// Tries a cached unlock, then CPCM_PASSPHRASE, then asks on the terminal.
+pub fn unlock(db: &Connection) -> Result<Option<Vault>, Box<dyn Error>> {
+    let Some(meta) = load_meta(db)? else {
+        return Ok(None)
+    };
+
+    if let Some(vault) = read_cached(db)? {
+        if let Ok(vault) = verify(&meta, vault) {
+            return Ok(Some(vault))
+        }
+        log::debug!("Cached unlock doesn't match, ignoring it");
+    }
+
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(p) => p,
+        Err(_) if io::stdin().is_terminal() => rpassword::prompt_password("Passphrase: ")?,
+        Err(_) => return Err(format!("API keys are encrypted. Set {} or run cpcm secrets unlock", PASSPHRASE_ENV).into())
+    };
+
+    Ok(Some(verify(&meta, Vault::derive(&passphrase, &meta.salt, meta.params.clone())?)?))
+}
+
+// Decrypt the API keys of servers about to be used, unlocking only if one
+// of them is actually encrypted
+pub fn open_keys(db: &Connection, servers: &mut [ServerRow]) -> Result<(), Box<dyn Error>> {
+    if !servers.iter().any(|s| is_encrypted(&s.apikey)) {
+        return Ok(())
+    }
+    decrypt_keys(unlock(db)?.as_ref(), servers)
+}
+
+// Same as open_keys for a caller that has already unlocked and needs the
+// vault again afterwards
+pub fn decrypt_keys(vault: Option<&Vault>, servers: &mut [ServerRow]) -> Result<(), Box<dyn Error>> {
+    if !servers.iter().any(|s| is_encrypted(&s.apikey)) {
+        return Ok(())
+    }
+    let vault = vault.ok_or("Found encrypted API keys but the secrets table is empty")?;
+
+    for server in servers.iter_mut().filter(|s| is_encrypted(&s.apikey)) {
+        server.apikey = vault.decrypt(&server.apikey)
+            .map_err(|e| format!("{}: {}", server.name, e))?;
+    }
+
+    Ok(())
+}
+
+// New passphrase from CPCM_NEW_PASSPHRASE, or asked for twice
+pub fn new_passphrase() -> Result<String, Box<dyn Error>> {
+    let passphrase = match std::env::var(NEW_PASSPHRASE_ENV) {
+        Ok(p) => p,
+        Err(_) if io::stdin().is_terminal() => {
+            let p = rpassword::prompt_password("New passphrase: ")?;
+            if p != rpassword::prompt_password("Repeat passphrase: ")? {
+                return Err("Passphrases don't match".into())
+            }
+            p
+        }
+        Err(_) => return Err(format!("Set {} or run this on a terminal", NEW_PASSPHRASE_ENV).into())
+    };
+
+    if passphrase.is_empty() {
+        return Err("Passphrase is empty".into())
+    }
+
+    Ok(passphrase)
+}
+
+// One file per database under the runtime directory, which is usually a
+// tmpfs that only the user can read and that goes away on logout. Without
+// one there is nowhere private to keep the key, so nothing gets cached.
+fn cache_path(db: &Connection) -> Option<PathBuf> {
+    let id = format!("{:x}", Sha256::digest(db.path().unwrap_or_default().as_bytes()));
+
+    dirs::runtime_dir().map(|dir| dir.join(format!("cpcm-unlock-{}", &id[..16])))
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+fn read_cached(db: &Connection) -> Result<Option<Vault>, Box<dyn Error>> {
+    let Some(path) = cache_path(db) else {
+        return Ok(None)
+    };
+    let Ok(json) = fs::read_to_string(path) else {
+        return Ok(None)
+    };
+    let Ok(cached) = serde_json::from_str::<CachedUnlock>(&json) else {
+        return Ok(None)
+    };
+    if cached.expires < now() {
+        forget_unlock(db)?;
+        return Ok(None)
+    }
+
+    let key: [u8; 32] = BASE64.decode(cached.key)?.try_into().map_err(|_| "Cached unlock has a bad key")?;
+    Ok(Some(Vault { key }))
+}
+
+pub fn cache_unlock(db: &Connection, vault: &Vault, ttl: Duration) -> Result<PathBuf, Box<dyn Error>> {
+    let path = cache_path(db)
+        .ok_or("No private runtime directory (XDG_RUNTIME_DIR) to keep the unlock in")?;
+    let cached = CachedUnlock { expires: now() + ttl.as_secs() as i64, key: BASE64.encode(vault.key) };
+
+    // Always start from a fresh file so a planted symlink or a file with
+    // looser permissions is never written through
+    forget_unlock(db)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(serde_json::to_string(&cached)?.as_bytes())?;
+
+    Ok(path)
+}
+
+pub fn forget_unlock(db: &Connection) -> Result<(), Box<dyn Error>> {
+    let Some(path) = cache_path(db) else {
+        return Ok(())
+    };
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(())
+    }
+}
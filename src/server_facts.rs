@@ -0,0 +1,157 @@
+use std::error::Error;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::error_types::WhmError;
+use crate::sql_strings::{FACTS_SELECT, FACTS_UPSERT};
+use crate::whm_client::WhmClient;
+
+// One partition from whmapi1 getdiskusage. Sizes are in 1K blocks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    pub mount: String,
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+    pub percentage: f64
+}
+
+// What a server told us about itself the last time we asked. Everything but
+// the version is optional: not every WHM version has every function, and a
+// fact it can't give is left NULL rather than failing the lot.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerFacts {
+    pub server: String,
+    // Unix time
+    pub collected: i64,
+    pub version: String,
+    pub tier: Option<String>,
+    pub os: Option<String>,
+    pub hostname: Option<String>,
+    pub load_1: Option<f64>,
+    pub load_5: Option<f64>,
+    pub load_15: Option<f64>,
+    pub accounts: Option<i64>,
+    pub disks: Vec<DiskUsage>
+}
+
+impl ServerFacts {
+    // The version call has to work, it's what tells us the server answers
+    // at all. The rest are best effort.
+    pub async fn collect(client: &WhmClient, collected: i64) -> Result<Self, WhmError> {
+        let server = client.server().name.clone();
+        let version = client.version().await?;
+
+        let tier = optional(&server, "tier", client.tier().await);
+        let os = optional(&server, "OS release", client.os_release().await);
+        let hostname = optional(&server, "hostname", client.hostname().await);
+        let load = optional(&server, "load average", client.load_average().await);
+        let accounts = optional(&server, "account count", client.account_count().await);
+        let disks = optional(&server, "disk usage", client.disk_usage().await).unwrap_or_default();
+
+        Ok(Self {
+            server,
+            collected,
+            version,
+            tier,
+            os,
+            hostname,
+            load_1: load.map(|l| l.0),
+            load_5: load.map(|l| l.1),
+            load_15: load.map(|l| l.2),
+            accounts,
+            disks
+        })
+    }
+
+    pub fn upsert(&self, db: &Connection) -> Result<(), Box<dyn Error>> {
+        db.execute(&FACTS_UPSERT(), rusqlite::named_params! {
+            ":server_name": self.server,
+            ":collected": self.collected,
+            ":version": self.version,
+            ":tier": self.tier,
+            ":os": self.os,
+            ":hostname": self.hostname,
+            ":load_1": self.load_1,
+            ":load_5": self.load_5,
+            ":load_15": self.load_15,
+            ":accounts": self.accounts,
+            ":disks": serde_json::to_string(&self.disks)?
+        })?;
+
+        Ok(())
+    }
+
+    // Every server's facts, each with when they were collected as UTC text
+    pub fn select_all(db: &Connection) -> Result<Vec<(Self, String)>, Box<dyn Error>> {
+        let mut stmt = db.prepare(&FACTS_SELECT())?;
+        let rows = stmt.query_map([], |r| Ok((
+            Self {
+                server: r.get(0)?,
+                collected: r.get(1)?,
+                version: r.get(2)?,
+                tier: r.get(3)?,
+                os: r.get(4)?,
+                hostname: r.get(5)?,
+                load_1: r.get(6)?,
+                load_5: r.get(7)?,
+                load_15: r.get(8)?,
+                accounts: r.get(9)?,
+                disks: Vec::new()
+            },
+            r.get::<_, String>(10)?,
+            r.get::<_, String>(11)?
+        )))?;
+
+        let mut facts = Vec::new();
+        for row in rows {
+            let (mut f, disks, collected) = row?;
+            f.disks = serde_json::from_str(&disks)?;
+            facts.push((f, collected));
+        }
+
+        Ok(facts)
+    }
+
+    // Fullest partition, for the one line summary
+    pub fn fullest_disk(&self) -> Option<&DiskUsage> {
+        self.disks.iter().max_by(|a, b| a.percentage.total_cmp(&b.percentage))
+    }
+}
+
+fn optional<T>(server: &str, fact: &str, result: Result<T, WhmError>) -> Option<T> {
+    match result {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::debug!("{}: no {}. {}", server, fact, e);
+            None
+        }
+    }
+}
+
+// WHM versions all start with 11, so "110" is short for "11.110"
+fn expand_version(query: &str) -> String {
+    match query.contains('.') {
+        true => query.to_string(),
+        false => format!("11.{}", query)
+    }
+}
+
+// Does a WHM version like 11.110.0.17 match what was asked for? A prefix by
+// whole components: "110" matches 11.110.0.17 but not 11.1100.0.1.
+pub fn version_matches(version: &str, query: &str) -> bool {
+    let query = expand_version(query);
+    let (have, want): (Vec<&str>, Vec<&str>) = (version.split('.').collect(), query.split('.').collect());
+
+    want.len() <= have.len() && have.iter().zip(&want).all(|(h, w)| h == w)
+}
+
+// Is the version older than the one given, compared component by component
+// as numbers? Same shorthand as version_matches.
+pub fn version_older_than(version: &str, query: &str) -> bool {
+    let query = expand_version(query);
+    let parts = |v: &str| v.split('.').map(|p| p.parse::<u64>().unwrap_or(0)).collect::<Vec<_>>();
+
+    parts(version) < parts(&query)
+}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::apikey::KeySource;
+
+// One server in an inventory file for server import. Inventory files never
+// hold keys themselves, only where to get them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryRow {
+    pub name: String,
+    pub ip: String,
+    pub user: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    // env:NAME, file:PATH or prompt
+    pub apikey: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum InventoryFormat {
+    Csv,
+    Yaml
+}
+
+// A row that checked out, with its position in the file
+#[derive(Debug, Clone)]
+pub struct InventoryEntry {
+    pub row: usize,
+    pub server: InventoryRow,
+    pub key: KeySource
+}
+
+// A row that didn't make it
+#[derive(Debug, Clone)]
+pub struct SkippedRow {
+    // Data line for CSV, list entry for YAML. Both count from 1.
+    pub row: usize,
+    pub name: String,
+    pub reason: String
+}
+
+impl InventoryFormat {
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+            Some("csv") => Ok(InventoryFormat::Csv),
+            Some("yaml" | "yml") => Ok(InventoryFormat::Yaml),
+            _ => Err(format!("Can't tell the format of {}. Use --format csv or --format yaml", path.display()).into())
+        }
+    }
+}
+
+impl InventoryRow {
+    // Checks that don't need the database
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name is empty".to_string())
+        }
+        if self.ip.trim().parse::<IpAddr>().is_err() {
+            return Err(format!("{} is not an IP address", self.ip))
+        }
+        if self.user.trim().is_empty() {
+            return Err("user is empty".to_string())
+        }
+
+        Ok(())
+    }
+
+    fn not_empty(s: &Option<String>) -> Option<String> {
+        s.as_ref().map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+    }
+
+    pub fn hostname(&self) -> Option<String> {
+        InventoryRow::not_empty(&self.hostname)
+    }
+
+    pub fn group(&self) -> Option<String> {
+        InventoryRow::not_empty(&self.group)
+    }
+}
+
+// Every row that parses and validates, each with its key source, plus the
+// ones that don't. Names must be unique within the file.
+pub fn read_inventory(path: &Path, format: InventoryFormat)
+-> Result<(Vec<InventoryEntry>, Vec<SkippedRow>), Box<dyn Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+
+    let parsed: Vec<(usize, Result<InventoryRow, String>)> = match format {
+        InventoryFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(text.as_bytes())
+            .deserialize::<InventoryRow>()
+            .enumerate()
+            .map(|(i, r)| (i + 1, r.map_err(|e| e.to_string())))
+            .collect(),
+        InventoryFormat::Yaml => serde_yaml::from_str::<Vec<serde_yaml::Value>>(&text)
+            .map_err(|e| format!("{} is not a YAML list of servers: {}", path.display(), e))?
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i + 1, serde_yaml::from_value::<InventoryRow>(v).map_err(|e| e.to_string())))
+            .collect()
+    };
+
+    let mut rows = Vec::new();
+    let mut skipped = Vec::new();
+    let mut seen = HashSet::new();
+    for (row, parsed) in parsed {
+        let r = match parsed {
+            Ok(r) => r,
+            Err(reason) => {
+                skipped.push(SkippedRow { row, name: String::new(), reason });
+                continue
+            }
+        };
+
+        let checked = r.validate().and_then(|_| match seen.insert(r.name.trim().to_string()) {
+            true => KeySource::parse(&r.apikey),
+            false => Err(format!("{} appears more than once", r.name.trim()))
+        });
+        match checked {
+            Ok(key) => rows.push(InventoryEntry { row, server: r, key }),
+            Err(reason) => skipped.push(SkippedRow { row, name: r.name, reason })
+        }
+    }
+
+    Ok((rows, skipped))
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use clap::Args;
+use rusqlite::Connection;
+
+use crate::config::Config;
+use crate::sqlite_types::ServerRow;
+use crate::sql_strings::{SERVER_DOMAIN_STATS, SERVER_LAST_SYNC, TAGS_SELECT};
+
+// Which servers a command should act on. Flatten this into any command that
+// works on more than one server. No flags means every server.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ServerSelection {
+    // Only the server with this name
+    #[arg(long)]
+    pub server: Option<String>,
+
+    // Only servers in this group
+    #[arg(long)]
+    pub group: Option<String>,
+
+    // Only servers whose tags match, e.g. dc=fra,role=shared
+    #[arg(long, value_parser = TagSelector::parse)]
+    pub tag: Option<TagSelector>,
+}
+
+// One term of a tag selector
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagTerm {
+    // key=value
+    Equals(String, String),
+    // key!=value, also true when the key isn't set at all
+    NotEquals(String, String),
+    // key on its own, set to anything
+    Has(String)
+}
+
+// Comma separated terms that all have to hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSelector {
+    pub terms: Vec<TagTerm>
+}
+
+// Tags of one server, key to value
+pub type Tags = HashMap<String, String>;
+
+// Keys and values end up in selectors and on command lines, so they're kept
+// to characters that never need quoting and can't be mistaken for syntax.
+pub fn check_tag_part(what: &str, s: &str) -> Result<(), String> {
+    if s.is_empty() {
+        return Err(format!("tag {} is empty", what))
+    }
+    match s.chars().find(|c| !(c.is_ascii_alphanumeric() || "-_.:/".contains(*c))) {
+        Some(c) => Err(format!("tag {} {} can't contain '{}'", what, s, c)),
+        None => Ok(())
+    }
+}
+
+impl TagSelector {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let terms = s.split(',')
+            .map(|term| {
+                let term = term.trim();
+                let parsed = match (term.split_once("!="), term.split_once('=')) {
+                    (Some((k, v)), _) => TagTerm::NotEquals(k.trim().to_string(), v.trim().to_string()),
+                    (None, Some((k, v))) => TagTerm::Equals(k.trim().to_string(), v.trim().to_string()),
+                    (None, None) => TagTerm::Has(term.to_string())
+                };
+                match &parsed {
+                    TagTerm::Equals(k, v) | TagTerm::NotEquals(k, v) => check_tag_part("key", k)
+                        .and_then(|_| check_tag_part("value", v)),
+                    TagTerm::Has(k) => check_tag_part("key", k)
+                }?;
+
+                Ok(parsed)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { terms })
+    }
+
+    pub fn matches(&self, tags: &Tags) -> bool {
+        self.terms.iter().all(|t| match t {
+            TagTerm::Equals(k, v) => tags.get(k) == Some(v),
+            TagTerm::NotEquals(k, v) => tags.get(k) != Some(v),
+            TagTerm::Has(k) => tags.contains_key(k)
+        })
+    }
+}
+
+impl ServerSelection {
+    pub fn select(&self, db: &Connection, config: &Config) -> Result<Vec<ServerRow>, Box<dyn Error>> {
+        let tags = match &self.tag {
+            Some(_) => tags_by_server(db)?,
+            None => HashMap::new()
+        };
+        let none = Tags::new();
+        let servers: Vec<ServerRow> = ServerRow::select_all(db, config)?
+            .into_iter()
+            .filter(|s| self.server.as_ref().is_none_or(|n| *n == s.name))
+            .filter(|s| self.group.as_ref().is_none_or(|g| s.group.as_ref() == Some(g)))
+            .filter(|s| self.tag.as_ref().is_none_or(|t| t.matches(tags.get(&s.name).unwrap_or(&none))))
+            .collect();
+
+        if let (Some(name), true) = (&self.server, servers.is_empty()) {
+            return Err(format!("No server named {}", name).into())
+        }
+
+        Ok(servers)
+    }
+}
+
+// Every server's tags. Servers without any are missing from the map.
+pub fn tags_by_server(db: &Connection) -> Result<HashMap<String, Tags>, Box<dyn Error>> {
+    let mut stmt = db.prepare(&TAGS_SELECT())?;
+    let mut rows = stmt.query([])?;
+    let mut tags: HashMap<String, Tags> = HashMap::new();
+    while let Some(r) = rows.next()? {
+        tags.entry(r.get(0)?).or_default().insert(r.get(1)?, r.get(2)?);
+    }
+
+    Ok(tags)
+}
+
+// Newest lastupdated of each server's domains. Servers that never synced
+// anything are missing from the map.
+pub fn last_sync_times(db: &Connection, config: &Config) -> Result<HashMap<String, i64>, Box<dyn Error>> {
+    let mut stmt = db.prepare(&SERVER_LAST_SYNC(config))?;
+    let times = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(times)
+}
+
+// Domain count and newest sync time (UTC, as text) of each server
+pub fn domain_stats(db: &Connection, config: &Config) -> Result<HashMap<String, (i64, String)>, Box<dyn Error>> {
+    let mut stmt = db.prepare(&SERVER_DOMAIN_STATS(config))?;
+    let stats = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, (r.get(1)?, r.get(2)?))))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(stats)
+}
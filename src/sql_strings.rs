@@ -3,7 +3,7 @@ use crate::config::Config;
 
 #[allow(non_snake_case)]
 pub fn SQLSCHEMA(config: &Config) -> String {
-    return format!(r#"
+    format!(r#"
 
 CREATE TABLE IF NOT EXISTS {}(
   `name`     TEXT,
@@ -44,23 +44,137 @@ CREATE INDEX domain_idx ON `{}`(`domain`);
 "#, config.tabname_server(), config.tabname_domain(), config.tabname_server(), config.tabname_domain())
 }
 
+// Schema changes made after the initial SQLSCHEMA, applied in order by
+// database::migrate. Only ever append to this list.
+#[allow(non_snake_case)]
+pub fn SQLMIGRATIONS(config: &Config) -> Vec<String> {
+    vec![
+        // Per-server TLS verification
+        format!(r#"
+ALTER TABLE {0} ADD COLUMN `tls_mode` TEXT NOT NULL DEFAULT 'system';
+ALTER TABLE {0} ADD COLUMN `tls_ca_path` TEXT;
+ALTER TABLE {0} ADD COLUMN `tls_fingerprint` TEXT;
+"#, config.tabname_server()),
+        // Where and how to reach whmapi1
+        format!(r#"
+ALTER TABLE {0} ADD COLUMN `scheme` TEXT NOT NULL DEFAULT 'https';
+ALTER TABLE {0} ADD COLUMN `port` INTEGER NOT NULL DEFAULT 2087;
+ALTER TABLE {0} ADD COLUMN `base_path` TEXT NOT NULL DEFAULT '';
+ALTER TABLE {0} ADD COLUMN `use_hostname` INTEGER NOT NULL DEFAULT 0;
+"#, config.tabname_server()),
+        // Per-server overrides of the request settings in Config. NULL means
+        // use the config value.
+        format!(r#"
+ALTER TABLE {0} ADD COLUMN `connect_timeout_secs` INTEGER;
+ALTER TABLE {0} ADD COLUMN `read_timeout_secs` INTEGER;
+ALTER TABLE {0} ADD COLUMN `max_retries` INTEGER;
+ALTER TABLE {0} ADD COLUMN `retry_backoff_ms` INTEGER;
+ALTER TABLE {0} ADD COLUMN `requests_per_second` REAL;
+"#, config.tabname_server()),
+        // Every insert, update and delete done by domain sync. old_values and
+        // new_values are JSON objects of the columns involved.
+        r#"
+CREATE TABLE IF NOT EXISTS domain_history(
+  `id`          INTEGER PRIMARY KEY AUTOINCREMENT,
+  `timestamp`   INTEGER NOT NULL,
+  `server_name` TEXT NOT NULL,
+  `domain`      TEXT NOT NULL,
+  `action`      TEXT NOT NULL,
+  `old_values`  TEXT,
+  `new_values`  TEXT
+);
+CREATE INDEX IF NOT EXISTS domain_history_domain_idx ON domain_history(`domain`);
+CREATE INDEX IF NOT EXISTS domain_history_timestamp_idx ON domain_history(`timestamp`);
+"#.to_string(),
+        // How to reach the server: direct, through a proxy or an ssh tunnel
+        format!(r#"
+ALTER TABLE {0} ADD COLUMN `transport` TEXT NOT NULL DEFAULT 'direct';
+ALTER TABLE {0} ADD COLUMN `transport_target` TEXT;
+"#, config.tabname_server()),
+        // Salt, KDF parameters and passphrase check for encrypted API keys
+        r#"
+CREATE TABLE IF NOT EXISTS secrets(
+  `name`  TEXT PRIMARY KEY,
+  `value` TEXT NOT NULL
+);
+"#.to_string(),
+        // API token rotation. token_name is the WHM name of the token in
+        // apikey when cpcm knows it. The pending_ columns hold a token that
+        // was created but isn't in use yet, stale_token_name one that was
+        // replaced but not yet revoked.
+        format!(r#"
+ALTER TABLE {0} ADD COLUMN `token_name` TEXT;
+ALTER TABLE {0} ADD COLUMN `pending_apikey` TEXT;
+ALTER TABLE {0} ADD COLUMN `pending_token_name` TEXT;
+ALTER TABLE {0} ADD COLUMN `stale_token_name` TEXT;
+"#, config.tabname_server()),
+        // Latest facts about each server, see ServerFacts. disks is a JSON
+        // list of partitions.
+        r#"
+CREATE TABLE IF NOT EXISTS server_facts(
+  `server_name` TEXT PRIMARY KEY,
+  `collected`   INTEGER NOT NULL,
+  `version`     TEXT NOT NULL,
+  `tier`        TEXT,
+  `os`          TEXT,
+  `hostname`    TEXT,
+  `load_1`      REAL,
+  `load_5`      REAL,
+  `load_15`     REAL,
+  `accounts`    INTEGER,
+  `disks`       TEXT NOT NULL DEFAULT '[]'
+);
+"#.to_string(),
+        // key=value labels on servers, at most one value per key
+        r#"
+CREATE TABLE IF NOT EXISTS server_tags(
+  `server_name` TEXT NOT NULL,
+  `key`         TEXT NOT NULL,
+  `value`       TEXT NOT NULL,
+  PRIMARY KEY(`server_name`, `key`)
+);
+"#.to_string(),
+    ]
+}
+
 #[allow(non_snake_case)]
 pub fn SERVERADD_UPSERT(config: &Config) -> String {
-    return format!(r#"
-INSERT INTO {}(`name`, `ip`, `user`, `apikey`, `hostname`, `group`)
-VALUES (:name, :ip, :user, :apikey, :hostname, :group)
+    format!(r#"
+INSERT INTO {}(`name`, `ip`, `user`, `apikey`, `hostname`, `group`, `tls_mode`, `tls_ca_path`, `tls_fingerprint`,
+    `scheme`, `port`, `base_path`, `use_hostname`,
+    `connect_timeout_secs`, `read_timeout_secs`, `max_retries`, `retry_backoff_ms`, `requests_per_second`,
+    `transport`, `transport_target`)
+VALUES (:name, :ip, :user, :apikey, :hostname, :group, :tls_mode, :tls_ca_path, :tls_fingerprint,
+    :scheme, :port, :base_path, :use_hostname,
+    :connect_timeout_secs, :read_timeout_secs, :max_retries, :retry_backoff_ms, :requests_per_second,
+    :transport, :transport_target)
 ON CONFLICT (name, ip) DO UPDATE SET
     `name`=excluded.`name`,
     `ip`=excluded.`ip`,
     `user`=excluded.`user`,
     `apikey`=excluded.`apikey`,
+    `token_name`=NULL,
     `hostname`=excluded.`hostname`,
-    `group`=excluded.`group`;"#, config.tabname_server())
+    `group`=excluded.`group`,
+    `tls_mode`=excluded.`tls_mode`,
+    `tls_ca_path`=excluded.`tls_ca_path`,
+    `tls_fingerprint`=excluded.`tls_fingerprint`,
+    `scheme`=excluded.`scheme`,
+    `port`=excluded.`port`,
+    `base_path`=excluded.`base_path`,
+    `use_hostname`=excluded.`use_hostname`,
+    `connect_timeout_secs`=excluded.`connect_timeout_secs`,
+    `read_timeout_secs`=excluded.`read_timeout_secs`,
+    `max_retries`=excluded.`max_retries`,
+    `retry_backoff_ms`=excluded.`retry_backoff_ms`,
+    `requests_per_second`=excluded.`requests_per_second`,
+    `transport`=excluded.`transport`,
+    `transport_target`=excluded.`transport_target`;"#, config.tabname_server())
 }
 
 #[allow(non_snake_case)]
 pub fn DOMAINSYNC_UPSERT(config: &Config) -> String {
-    return format!(r#"
+    format!(r#"
 INSERT INTO `{}`(docroot, domain, domain_type, ipv4, ipv4_ssl, ipv6, ipv6_is_dedicated, modsecurity_enabled, parent_domain, php_version, port, port_ssl, user, user_owner, server_name, server_ip, lastupdated)
 VALUES(:docroot, :domain, :domain_type, :ipv4, :ipv4_ssl, :ipv6, :ipv6_is_dedicated, :modsecurity_enabled, :parent_domain, :php_version, :port, :port_ssl, :user, :user_owner, :server_name, :server_ip, :lastupdate)
     ON CONFLICT (server_name, domain) DO UPDATE SET
@@ -81,6 +195,248 @@ VALUES(:docroot, :domain, :domain_type, :ipv4, :ipv4_ssl, :ipv6, :ipv6_is_dedica
         user=excluded.user,
         user_owner=excluded.user_owner,
         lastupdated=excluded.lastupdated
-    WHERE lastupdated>excluded.lastupdated;"#, config.tabname_domain())
+    WHERE excluded.lastupdated>=lastupdated;"#, config.tabname_domain())
+}
+
+// Drop a domain that one server no longer reports
+#[allow(non_snake_case)]
+pub fn DOMAINSYNC_REMOVE_STALE(config: &Config) -> String {
+    format!("DELETE FROM `{}` WHERE server_name = :server_name AND domain = :domain;", config.tabname_domain())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_SELECT(config: &Config) -> String {
+    format!(r#"
+SELECT `name`, `ip`, `user`, `apikey`, `hostname`, `group`, `tls_mode`, `tls_ca_path`, `tls_fingerprint`,
+    `scheme`, `port`, `base_path`, `use_hostname`,
+    `connect_timeout_secs`, `read_timeout_secs`, `max_retries`, `retry_backoff_ms`, `requests_per_second`,
+    `transport`, `transport_target`
+FROM {}"#, config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_SET_TLS(config: &Config) -> String {
+    format!(r#"
+UPDATE {} SET `tls_mode`=:tls_mode, `tls_ca_path`=:tls_ca_path, `tls_fingerprint`=:tls_fingerprint
+WHERE `name`=:name AND `ip`=:ip;"#, config.tabname_server())
+}
+
+// Used by server edit inside a transaction with deferred foreign keys, the
+// domains are moved over before commit. A cleared hostname also turns
+// use_hostname off.
+#[allow(non_snake_case)]
+pub fn SERVER_UPDATE(config: &Config) -> String {
+    format!(r#"
+UPDATE {} SET `name`=:name, `ip`=:ip, `user`=:user, `hostname`=:hostname, `group`=:group,
+    `apikey`=COALESCE(:apikey, `apikey`), `token_name`=CASE WHEN :apikey IS NULL THEN `token_name` END,
+    `use_hostname`=(`use_hostname` AND :hostname != 'NULL')
+WHERE `name`=:old_name AND `ip`=:old_ip;"#, config.tabname_server())
+}
+
+// Rows for the same name under other IPs, left behind by server add before
+// it refused IP changes
+#[allow(non_snake_case)]
+pub fn SERVER_DELETE_DUPLICATES(config: &Config) -> String {
+    format!("DELETE FROM {} WHERE `name`=:name AND `ip`!=:ip;", config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_SET_APIKEY(config: &Config) -> String {
+    format!("UPDATE {} SET `apikey`=:apikey WHERE `name`=:name AND `ip`=:ip;", config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_SET_PENDING_APIKEY(config: &Config) -> String {
+    format!("UPDATE {} SET `pending_apikey`=:apikey WHERE `name`=:name AND `ip`=:ip;", config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_PENDING_KEYS(config: &Config) -> String {
+    format!("SELECT `name`, `ip`, `pending_apikey` FROM {} WHERE `pending_apikey` IS NOT NULL;", config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn TOKEN_STATE_SELECT(config: &Config) -> String {
+    format!(r#"
+SELECT `pending_apikey`, `pending_token_name`, `stale_token_name` FROM {}
+WHERE `name`=:name AND `ip`=:ip;"#, config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn TOKEN_SET_PENDING(config: &Config) -> String {
+    format!(r#"
+UPDATE {} SET `pending_apikey`=:apikey, `pending_token_name`=:token_name
+WHERE `name`=:name AND `ip`=:ip;"#, config.tabname_server())
+}
+
+// Swap the pending token in. The one it replaces is remembered for revoking,
+// by its recorded name or else whatever name the user gave.
+#[allow(non_snake_case)]
+pub fn TOKEN_PROMOTE(config: &Config) -> String {
+    format!(r#"
+UPDATE {} SET `apikey`=`pending_apikey`, `token_name`=`pending_token_name`,
+    `stale_token_name`=COALESCE(`token_name`, :old_token_name),
+    `pending_apikey`=NULL, `pending_token_name`=NULL
+WHERE `name`=:name AND `ip`=:ip AND `pending_apikey` IS NOT NULL;"#, config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn TOKEN_CLEAR_PENDING(config: &Config) -> String {
+    format!("UPDATE {} SET `pending_apikey`=NULL, `pending_token_name`=NULL WHERE `name`=:name AND `ip`=:ip;",
+        config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn TOKEN_CLEAR_STALE(config: &Config) -> String {
+    format!("UPDATE {} SET `stale_token_name`=NULL WHERE `name`=:name AND `ip`=:ip;", config.tabname_server())
+}
+
+// Every row for a server name, whatever IPs it was added with
+#[allow(non_snake_case)]
+pub fn SERVER_DELETE(config: &Config) -> String {
+    format!("DELETE FROM {} WHERE `name`=:name;", config.tabname_server())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_LAST_SYNC(config: &Config) -> String {
+    format!("SELECT server_name, MAX(lastupdated) FROM `{}` GROUP BY server_name;", config.tabname_domain())
+}
+
+#[allow(non_snake_case)]
+pub fn SERVER_DOMAIN_STATS(config: &Config) -> String {
+    format!(r#"
+SELECT server_name, COUNT(*) AS domains, datetime(MAX(lastupdated), 'unixepoch') AS last_sync
+FROM `{}` GROUP BY server_name;"#, config.tabname_domain())
+}
+
+// where_clause comes from DomainFilter::to_sql, values are bound separately
+#[allow(non_snake_case)]
+pub fn DOMAIN_SELECT_WHERE(config: &Config, where_clause: &str) -> String {
+    format!("SELECT * FROM `{}` WHERE {} ORDER BY server_name, domain;", config.tabname_domain(), where_clause)
+}
+
+#[allow(non_snake_case)]
+pub fn DOMAIN_SELECT_BY_SERVER(config: &Config) -> String {
+    format!("SELECT * FROM `{}` WHERE server_name = :server_name ORDER BY domain;", config.tabname_domain())
+}
+
+#[allow(non_snake_case)]
+pub fn DOMAIN_MOVE_SERVER(config: &Config) -> String {
+    format!("UPDATE `{}` SET server_name = :name, server_ip = :ip WHERE server_name = :old_name;",
+        config.tabname_domain())
+}
+
+#[allow(non_snake_case)]
+pub fn DOMAIN_DELETE_BY_SERVER(config: &Config) -> String {
+    format!("DELETE FROM `{}` WHERE server_name = :server_name;", config.tabname_domain())
+}
+
+#[allow(non_snake_case)]
+pub fn HISTORY_INSERT() -> String {
+    r#"
+INSERT INTO domain_history(`timestamp`, `server_name`, `domain`, `action`, `old_values`, `new_values`)
+VALUES (:timestamp, :server_name, :domain, :action, :old_values, :new_values);"#.to_string()
+}
+
+// Newest first. :domain and :since are optional, pass NULL to skip them.
+#[allow(non_snake_case)]
+pub fn HISTORY_SELECT() -> String {
+    r#"
+SELECT datetime(`timestamp`, 'unixepoch') AS `time`, `server_name`, `domain`, `action`, `old_values`, `new_values`
+FROM domain_history
+WHERE (:domain IS NULL OR `domain` = :domain)
+  AND (:since IS NULL OR `timestamp` >= :since)
+  AND (:server_name IS NULL OR `server_name` = :server_name)
+ORDER BY `timestamp` DESC, `id` DESC;"#.to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn HISTORY_DELETE_BY_SERVER() -> String {
+    "DELETE FROM domain_history WHERE `server_name` = :server_name;".to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn HISTORY_RENAME_SERVER() -> String {
+    "UPDATE domain_history SET `server_name` = :name WHERE `server_name` = :old_name;".to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn SECRETS_SELECT() -> String {
+    "SELECT `name`, `value` FROM secrets;".to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn SECRETS_UPSERT() -> String {
+    "INSERT INTO secrets(`name`, `value`) VALUES (:name, :value) ON CONFLICT(`name`) DO UPDATE SET `value`=excluded.`value`;"
+        .to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn FACTS_UPSERT() -> String {
+    r#"
+INSERT INTO server_facts(`server_name`, `collected`, `version`, `tier`, `os`, `hostname`,
+    `load_1`, `load_5`, `load_15`, `accounts`, `disks`)
+VALUES (:server_name, :collected, :version, :tier, :os, :hostname, :load_1, :load_5, :load_15, :accounts, :disks)
+ON CONFLICT(`server_name`) DO UPDATE SET
+    `collected`=excluded.`collected`,
+    `version`=excluded.`version`,
+    `tier`=excluded.`tier`,
+    `os`=excluded.`os`,
+    `hostname`=excluded.`hostname`,
+    `load_1`=excluded.`load_1`,
+    `load_5`=excluded.`load_5`,
+    `load_15`=excluded.`load_15`,
+    `accounts`=excluded.`accounts`,
+    `disks`=excluded.`disks`;"#.to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn FACTS_SELECT() -> String {
+    r#"
+SELECT `server_name`, `collected`, `version`, `tier`, `os`, `hostname`, `load_1`, `load_5`, `load_15`, `accounts`, `disks`,
+    datetime(`collected`, 'unixepoch')
+FROM server_facts ORDER BY `server_name`;"#.to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn FACTS_DELETE_BY_SERVER() -> String {
+    "DELETE FROM server_facts WHERE `server_name` = :server_name;".to_string()
+}
+
+// A rename onto a name that had facts of its own drops those
+#[allow(non_snake_case)]
+pub fn FACTS_RENAME_SERVER() -> String {
+    "UPDATE OR REPLACE server_facts SET `server_name` = :name WHERE `server_name` = :old_name;".to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn TAGS_SELECT() -> String {
+    "SELECT `server_name`, `key`, `value` FROM server_tags ORDER BY `server_name`, `key`;".to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn TAGS_UPSERT() -> String {
+    r#"
+INSERT INTO server_tags(`server_name`, `key`, `value`) VALUES (:server_name, :key, :value)
+ON CONFLICT(`server_name`, `key`) DO UPDATE SET `value`=excluded.`value`;"#.to_string()
+}
+
+// Drops the tag only if it has the given value, or whatever value when
+// :value is NULL
+#[allow(non_snake_case)]
+pub fn TAGS_DELETE() -> String {
+    r#"
+DELETE FROM server_tags
+WHERE `server_name` = :server_name AND `key` = :key AND (:value IS NULL OR `value` = :value);"#.to_string()
+}
+
+#[allow(non_snake_case)]
+pub fn TAGS_DELETE_BY_SERVER() -> String {
+    "DELETE FROM server_tags WHERE `server_name` = :server_name;".to_string()
+}
+
+// Same as FACTS_RENAME_SERVER, tags already under the new name lose
+#[allow(non_snake_case)]
+pub fn TAGS_RENAME_SERVER() -> String {
+    "UPDATE OR REPLACE server_tags SET `server_name` = :name WHERE `server_name` = :old_name;".to_string()
 }
- 
@@ -1,10 +1,21 @@
 use serde::{Serialize, Deserialize};
-use rusqlite::Row;
+use rusqlite::{params, Connection, Row};
 use std::error::Error;
 
-#[derive(Debug, Clone)]
+use crate::config::Config;
+use crate::sql_strings::{DOMAIN_SELECT_BY_SERVER, SERVER_SELECT};
+use crate::tls::TlsMode;
+use crate::transport::Transport;
+
+// One comparison in a domain filter. Text values are bound as parameters,
+// never formatted into the SQL.
+#[derive(Debug, Clone, PartialEq)]
 pub enum SqlWhere {
+    // Glob with * and ? as wildcards, case insensitive for ASCII
     Like(String),
+    NotLike(String),
+    Equals(String),
+    NotEquals(String),
     GreaterThan(i32),
     LessThan(i32),
     EqualTo(i32),
@@ -12,14 +23,307 @@ pub enum SqlWhere {
     LessEqual(i32)
 }
 
-#[allow(dead_code)]
+// Columns of the domains table a filter can use, and whether they hold
+// integers. server is short for server_name.
+const FILTER_COLUMNS: &[(&str, bool)] = &[
+    ("server_name", false),
+    ("server_ip", false),
+    ("lastupdated", true),
+    ("docroot", false),
+    ("domain", false),
+    ("domain_type", false),
+    ("ipv4", false),
+    ("ipv4_ssl", false),
+    ("ipv6", false),
+    ("ipv6_is_dedicated", true),
+    ("modsecurity_enabled", true),
+    ("parent_domain", false),
+    ("php_version", false),
+    ("port", false),
+    ("port_ssl", false),
+    ("user", false),
+    ("user_owner", false)
+];
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SqlWhereFilter {
     colname: String,
     filter: SqlWhere,
 }
+
 impl SqlWhereFilter {
-    pub fn new() -> Self {
-        todo!()
+    // Fails for anything that isn't a column in FILTER_COLUMNS, which is
+    // what makes it safe to put colname into the SQL as is.
+    pub fn new(colname: &str, filter: SqlWhere) -> Result<Self, String> {
+        let colname = match colname {
+            "server" => "server_name",
+            c => c
+        };
+        if !FILTER_COLUMNS.iter().any(|(c, _)| *c == colname) {
+            let known: Vec<&str> = FILTER_COLUMNS.iter().map(|(c, _)| *c).collect();
+            return Err(format!("unknown column {}. Filter on one of: server, {}", colname, known.join(", ")))
+        }
+
+        Ok(Self { colname: colname.to_string(), filter })
+    }
+
+    // From one term as written, e.g. php_version=ea-php74. Numbers are
+    // required for <, >, <= and >=, and = compares as numbers on integer
+    // columns.
+    fn from_term(colname: &str, op: &str, value: String) -> Result<Self, String> {
+        let integer = FILTER_COLUMNS.iter().any(|(c, i)| *i && *c == colname);
+        let number = || value.parse::<i32>().map_err(|_| format!("{} {} needs a number, not {}", colname, op, value));
+        let filter = match op {
+            "=" if integer => SqlWhere::EqualTo(number()?),
+            "=" => SqlWhere::Equals(value),
+            "!=" => SqlWhere::NotEquals(value),
+            "~" => SqlWhere::Like(value),
+            "!~" => SqlWhere::NotLike(value),
+            ">" => SqlWhere::GreaterThan(number()?),
+            "<" => SqlWhere::LessThan(number()?),
+            ">=" => SqlWhere::GreaterEqual(number()?),
+            "<=" => SqlWhere::LessEqual(number()?),
+            _ => return Err(format!("unknown operator {}", op))
+        };
+
+        SqlWhereFilter::new(colname, filter)
+    }
+
+    // This comparison as SQL with one ? and the value that goes with it
+    pub fn to_sql(&self) -> (String, rusqlite::types::Value) {
+        let c = &self.colname;
+        let n = |op: &str, v: &i32| (format!("CAST(`{}` AS INTEGER) {} ?", c, op), (*v).into());
+        match &self.filter {
+            SqlWhere::Like(v) => (format!("`{}` LIKE ? ESCAPE '\\'", c), glob_to_like(v).into()),
+            SqlWhere::NotLike(v) => (format!("`{}` NOT LIKE ? ESCAPE '\\'", c), glob_to_like(v).into()),
+            SqlWhere::Equals(v) => (format!("`{}` = ?", c), v.clone().into()),
+            SqlWhere::NotEquals(v) => (format!("`{}` != ?", c), v.clone().into()),
+            SqlWhere::GreaterThan(v) => n(">", v),
+            SqlWhere::LessThan(v) => n("<", v),
+            SqlWhere::EqualTo(v) => n("=", v),
+            SqlWhere::GreaterEqual(v) => n(">=", v),
+            SqlWhere::LessEqual(v) => n("<=", v)
+        }
+    }
+}
+
+// * and ? to LIKE's % and _, escaping any % and _ that were there already
+fn glob_to_like(glob: &str) -> String {
+    glob.chars().map(|c| match c {
+        '*' => "%".to_string(),
+        '?' => "_".to_string(),
+        '%' | '_' | '\\' => format!("\\{}", c),
+        c => c.to_string()
+    }).collect()
+}
+
+// A parsed `domain --filter` expression: terms like column=value joined with
+// and/or, and binding tighter than or. Operators are = != ~ !~ > < >= <=,
+// where ~ is a glob match. Values with spaces go in quotes.
+//
+//   php_version=ea-php74 and modsecurity_enabled=0 and server~fra*
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainFilter {
+    // Any of these groups, where every filter in a group has to match
+    any: Vec<Vec<SqlWhereFilter>>
+}
+
+impl DomainFilter {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let mut chars = expr.chars().peekable();
+        let mut any = vec![Vec::new()];
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let colname: String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_'))
+                .collect();
+            if colname.is_empty() {
+                return match chars.peek() {
+                    Some(c) => Err(format!("expected a column name, found '{}'", c)),
+                    None => Err("expected a column name at the end of the filter".to_string())
+                }
+            }
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let op: String = std::iter::from_fn(|| chars.next_if(|c| "=!~<>".contains(*c))).collect();
+            if op.is_empty() {
+                return Err(format!("expected an operator after {}", colname))
+            }
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let value: String = match chars.next_if(|c| *c == '"' || *c == '\'') {
+                Some(quote) => {
+                    let value: String = std::iter::from_fn(|| chars.next_if(|c| *c != quote)).collect();
+                    if chars.next().is_none() {
+                        return Err(format!("missing closing {} after {}{}", quote, colname, op))
+                    }
+                    value
+                },
+                None => std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect()
+            };
+            if value.is_empty() {
+                return Err(format!("expected a value after {}{}", colname, op))
+            }
+            any.last_mut().unwrap().push(SqlWhereFilter::from_term(&colname, &op, value)?);
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break
+            }
+            let word: String = std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect();
+            match word.to_lowercase().as_str() {
+                "and" => (),
+                "or" => any.push(Vec::new()),
+                _ => return Err(format!("expected and or or, found {}", word))
+            }
+        }
+
+        Ok(Self { any })
+    }
+
+    // Everything after WHERE, with the values for its ? in order
+    pub fn to_sql(&self) -> (String, Vec<rusqlite::types::Value>) {
+        let mut values = Vec::new();
+        let groups: Vec<String> = self.any.iter()
+            .map(|group| {
+                let terms: Vec<String> = group.iter()
+                    .map(|f| {
+                        let (sql, value) = f.to_sql();
+                        values.push(value);
+                        sql
+                    })
+                    .collect();
+                format!("({})", terms.join(" AND "))
+            })
+            .collect();
+
+        (groups.join(" OR "), values)
+    }
+
+    // What domain --name has always done, a substring of the domain
+    pub fn domain_contains(name: &str) -> Self {
+        let filter = SqlWhereFilter { colname: "domain".to_string(), filter: SqlWhere::Like(format!("*{}*", name)) };
+        Self { any: vec![vec![filter]] }
+    }
+
+    // Both this and the other one
+    pub fn and(self, other: DomainFilter) -> Self {
+        let any = self.any.iter()
+            .flat_map(|a| other.any.iter().map(move |b| [a.clone(), b.clone()].concat()))
+            .collect();
+
+        Self { any }
+    }
+}
+
+
+// Represents a row in the table of servers. server add stores missing optional
+// columns as the string "NULL" so treat that the same as an actual NULL.
+#[derive(Debug, Clone)]
+pub struct ServerRow {
+    pub name: String,
+    pub ip: String,
+    pub user: String,
+    pub apikey: String,
+    pub hostname: Option<String>,
+    pub group: Option<String>,
+    pub tls: TlsMode,
+    pub scheme: String,
+    pub port: u16,
+    pub base_path: String,
+    // Connect to hostname and only fall back to ip if that fails
+    pub use_hostname: bool,
+    // Overrides for the matching Config settings
+    pub connect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub requests_per_second: Option<f64>,
+    pub transport: Transport
+}
+
+impl ServerRow {
+    // A server with the same defaults server add gives it
+    pub fn new(name: String, ip: String, user: String, apikey: String) -> Self {
+        Self {
+            name,
+            ip,
+            user,
+            apikey,
+            hostname: None,
+            group: None,
+            tls: TlsMode::System,
+            scheme: "https".to_string(),
+            port: 2087,
+            base_path: String::new(),
+            use_hostname: false,
+            connect_timeout_secs: None,
+            read_timeout_secs: None,
+            max_retries: None,
+            retry_backoff_ms: None,
+            requests_per_second: None,
+            transport: Transport::Direct
+        }
+    }
+
+    pub fn from_row(r: &Row) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            name: r.get::<_, String>("name")?,
+            ip: r.get::<_, String>("ip")?,
+            user: r.get::<_, String>("user")?,
+            apikey: r.get::<_, String>("apikey")?,
+            hostname: ServerRow::not_null(r.get::<_, Option<String>>("hostname")?),
+            group: ServerRow::not_null(r.get::<_, Option<String>>("group")?),
+            tls: TlsMode::from_columns(
+                &r.get::<_, String>("tls_mode")?,
+                r.get::<_, Option<String>>("tls_ca_path")?,
+                r.get::<_, Option<String>>("tls_fingerprint")?
+            )?,
+            scheme: r.get::<_, String>("scheme")?,
+            port: r.get::<_, u16>("port")?,
+            base_path: r.get::<_, String>("base_path")?,
+            use_hostname: r.get::<_, bool>("use_hostname")?,
+            connect_timeout_secs: r.get::<_, Option<u64>>("connect_timeout_secs")?,
+            read_timeout_secs: r.get::<_, Option<u64>>("read_timeout_secs")?,
+            max_retries: r.get::<_, Option<u32>>("max_retries")?,
+            retry_backoff_ms: r.get::<_, Option<u64>>("retry_backoff_ms")?,
+            requests_per_second: r.get::<_, Option<f64>>("requests_per_second")?,
+            transport: Transport::from_columns(
+                &r.get::<_, String>("transport")?,
+                r.get::<_, Option<String>>("transport_target")?
+            )?
+        })
+    }
+
+    pub fn select_all(db: &Connection, config: &Config) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut stmt = db.prepare(&SERVER_SELECT(config))?;
+        let mut rows = stmt.query(params![])?;
+        let mut servers = Vec::new();
+        while let Some(row) = rows.next()? {
+            servers.push(ServerRow::from_row(row)?);
+        }
+
+        Ok(servers)
+    }
+
+    pub fn select_by_name(db: &Connection, config: &Config, name: &str) -> Result<Self, Box<dyn Error>> {
+        let mut stmt = db.prepare(&format!("{} WHERE `name` = :name", SERVER_SELECT(config)))?;
+        let mut rows = stmt.query(rusqlite::named_params! { ":name": name })?;
+        match rows.next()? {
+            Some(row) => ServerRow::from_row(row),
+            None => Err(format!("No server named {}", name).into())
+        }
+    }
+
+    // Host we try first. Only differs from ip when use_hostname is set.
+    pub fn connect_host(&self) -> &str {
+        match (&self.hostname, self.use_hostname) {
+            (Some(h), true) => h,
+            _ => &self.ip
+        }
+    }
+
+    fn not_null(s: Option<String>) -> Option<String> {
+        s.filter(|s| s != "NULL" && !s.is_empty())
     }
 }
 
@@ -85,6 +389,39 @@ impl DomainRow {
         })
     }
 
+    // Every column as (name, value), the way we'd show it to a user
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let s = |v: &Option<String>| DomainRow::nullable(v.clone());
+        let i = |v: &Option<i32>| v.map_or("NULL".to_string(), |v| v.to_string());
+        vec![
+            ("docroot", s(&self.docroot)),
+            ("domain", s(&self.domain)),
+            ("domain_type", s(&self.domain_type)),
+            ("ipv4", s(&self.ipv4)),
+            ("ipv4_ssl", s(&self.ipv4_ssl)),
+            ("ipv6", s(&self.ipv6)),
+            ("ipv6_is_dedicated", i(&self.ipv6_is_dedicated)),
+            ("modsecurity_enabled", i(&self.modsecurity_enabled)),
+            ("parent_domain", s(&self.parent_domain)),
+            ("php_version", s(&self.php_version)),
+            ("port", s(&self.port)),
+            ("port_ssl", s(&self.port_ssl)),
+            ("user", s(&self.user)),
+            ("user_owner", s(&self.user_owner))
+        ]
+    }
+
+    pub fn select_by_server(db: &Connection, config: &Config, server_name: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut stmt = db.prepare(&DOMAIN_SELECT_BY_SERVER(config))?;
+        let mut rows = stmt.query(rusqlite::named_params! { ":server_name": server_name })?;
+        let mut domains = Vec::new();
+        while let Some(row) = rows.next()? {
+            domains.push(DomainRow::from_row(row)?);
+        }
+
+        Ok(domains)
+    }
+
     // bless vim macros
     pub fn as_vec(self) -> Vec<String> {
         let ipv6 = match self.ipv6_is_dedicated {
@@ -127,10 +464,21 @@ impl DomainRow {
     }
 
     fn nullable_i32(s: Option<i32>) -> i32 {
-        match s {
-            Some(s) => s,
-            None => 0
-        }
+        s.unwrap_or_default()
+    }
+
+    // safe_unwrap every row, logging and dropping the ones missing required
+    // fields. This is what actually ends up in the domains table.
+    pub fn safe_unwrap_all(rows: Vec<Self>) -> Vec<Self> {
+        rows.into_iter()
+            .filter_map(|r| match r.safe_unwrap() {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    log::error!("Unable to unwrap row! {:?}", e);
+                    None
+                }
+            })
+            .collect()
     }
 
     pub fn safe_unwrap(self) -> Result<Self, Box<dyn Error>> {
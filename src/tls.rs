@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsConnector;
+
+use crate::transport::Transport;
+
+// How a server's certificate gets verified. Stored in the tls_mode,
+// tls_ca_path and tls_fingerprint columns of the servers table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlsMode {
+    // Operating system trust store
+    System,
+    // PEM bundle of CAs to trust instead of the system store
+    CaBundle(PathBuf),
+    // SHA-256 of the server's leaf certificate, hex without separators
+    Pinned(String)
+}
+
+impl TlsMode {
+    pub fn from_columns(mode: &str, ca_path: Option<String>, fingerprint: Option<String>)
+    -> Result<Self, Box<dyn Error>> {
+        match mode {
+            "system" => Ok(TlsMode::System),
+            "ca" => Ok(TlsMode::CaBundle(PathBuf::from(ca_path.ok_or("tls_mode is ca but tls_ca_path is empty")?))),
+            "pin" => Ok(TlsMode::Pinned(fingerprint.ok_or("tls_mode is pin but tls_fingerprint is empty")?)),
+            m => Err(format!("Unknown tls_mode {}", m).into())
+        }
+    }
+
+    // (tls_mode, tls_ca_path, tls_fingerprint)
+    pub fn as_columns(&self) -> (&'static str, Option<String>, Option<String>) {
+        match self {
+            TlsMode::System => ("system", None, None),
+            TlsMode::CaBundle(p) => ("ca", Some(p.display().to_string()), None),
+            TlsMode::Pinned(f) => ("pin", None, Some(f.clone()))
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+pub fn client_config(mode: &TlsMode) -> Result<ClientConfig, Box<dyn Error>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+
+    let config = match mode {
+        TlsMode::System => {
+            let mut roots = RootCertStore::empty();
+            let native = rustls_native_certs::load_native_certs();
+            for e in native.errors {
+                log::debug!("Error loading system certificate: {}", e);
+            }
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            log::debug!("Loaded {} system certificates, ignored {}", added, ignored);
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsMode::CaBundle(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("Unable to read CA bundle {}: {}", path.display(), e))? {
+                roots.add(cert.map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsMode::Pinned(fp) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(Some(fp.clone()))))
+            .with_no_client_auth()
+    };
+
+    Ok(config)
+}
+
+// SHA-256 of a DER certificate as lowercase hex
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Accepts fingerprints the way openssl and browsers print them
+// (AA:BB:..., any case) and returns the form stored in the database.
+pub fn normalize_fingerprint(s: &str) -> Result<String, Box<dyn Error>> {
+    let fp: String = s.trim().chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+
+    if fp.len() != 64 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} is not a SHA-256 fingerprint", s).into())
+    }
+
+    Ok(fp)
+}
+
+pub fn display_fingerprint(fp: &str) -> String {
+    fp.as_bytes()
+        .chunks(2)
+        .map(|c| String::from_utf8_lossy(c).to_uppercase())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Checks the leaf certificate against a pinned fingerprint instead of a CA.
+// With `expected` set to None any certificate is accepted, which is only
+// meant for fetch_fingerprint where we want to look at whatever is there.
+// Handshake signatures are always verified.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    expected: Option<String>,
+    provider: Arc<CryptoProvider>
+}
+
+impl FingerprintVerifier {
+    fn new(expected: Option<String>) -> Self {
+        Self { expected, provider: provider() }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(expected) = &self.expected else {
+            return Ok(ServerCertVerified::assertion())
+        };
+
+        let got = fingerprint(end_entity);
+        if &got == expected {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate fingerprint {} does not match pinned {}",
+                display_fingerprint(&got), display_fingerprint(expected)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Connect to host:port and return the fingerprint of whatever certificate
+// the server presents. Nothing is verified, that's up to the caller.
+pub async fn fetch_fingerprint(host: &str, port: u16, transport: &Transport) -> Result<String, Box<dyn Error>> {
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(None)))
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let server_name = ServerName::try_from(host.to_string())?;
+
+    log::debug!("Fetching certificate from {}:{}", host, port);
+    let timeout = Duration::from_secs(10);
+    // The tunnel, if any, has to stay up until the handshake is done
+    let (stream, _tunnel) = transport.connect(host, port, timeout).await?;
+    let conn = tokio::time::timeout(timeout, connector.connect(server_name, stream)).await
+        .map_err(|_| format!("Timed out connecting to {}:{}", host, port))??;
+
+    let (_, session) = conn.get_ref();
+    let leaf = session.peer_certificates()
+        .and_then(|c| c.first())
+        .ok_or_else(|| format!("{}:{} did not present a certificate", host, port))?;
+
+    Ok(fingerprint(leaf))
+}
@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::net::{IpAddr, TcpListener};
+use std::process::Stdio;
+use std::time::Duration;
+
+use std::sync::Arc;
+
+use base64::Engine;
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::time::Instant;
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::tls::{self, TlsMode};
+
+// What Transport::connect hands back: a plain TCP stream, or one wrapped in
+// TLS to an https:// proxy
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// How to reach a server. Stored in the transport and transport_target
+// columns of the servers table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Direct,
+    // http:// or https:// proxy URL, CONNECT is used for https servers
+    HttpProxy(String),
+    // socks5:// or socks5h:// proxy URL
+    Socks5(String),
+    // ssh destination (plus any extra ssh arguments) to forward through
+    Ssh(String)
+}
+
+impl Transport {
+    pub fn from_columns(transport: &str, target: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let target = || target.clone().ok_or(format!("transport is {} but transport_target is empty", transport));
+        match transport {
+            "direct" => Ok(Transport::Direct),
+            "http" => Ok(Transport::HttpProxy(target()?)),
+            "socks5" => Ok(Transport::Socks5(target()?)),
+            "ssh" => Ok(Transport::Ssh(target()?)),
+            t => Err(format!("Unknown transport {}", t).into())
+        }
+    }
+
+    // Picks HttpProxy or Socks5 by the URL scheme
+    pub fn from_proxy_url(proxy: &str) -> Result<Self, Box<dyn Error>> {
+        let url = Url::parse(proxy)?;
+        match url.scheme() {
+            "http" | "https" => Ok(Transport::HttpProxy(proxy.to_string())),
+            "socks5" | "socks5h" => Ok(Transport::Socks5(proxy.to_string())),
+            s => Err(format!("Unsupported proxy scheme {}. Use http, https, socks5 or socks5h", s).into())
+        }
+    }
+
+    // (transport, transport_target)
+    pub fn as_columns(&self) -> (&'static str, Option<String>) {
+        match self {
+            Transport::Direct => ("direct", None),
+            Transport::HttpProxy(p) => ("http", Some(p.clone())),
+            Transport::Socks5(p) => ("socks5", Some(p.clone())),
+            Transport::Ssh(t) => ("ssh", Some(t.clone()))
+        }
+    }
+
+    // Raw TCP stream to host:port through this transport. For things that
+    // can't go through reqwest, like grabbing a certificate. An ssh tunnel
+    // is returned along with the stream and has to outlive it.
+    pub async fn connect(&self, host: &str, port: u16, timeout: Duration)
+    -> Result<(Box<dyn Stream>, Option<SshTunnel>), Box<dyn Error>> {
+        let connect = async {
+            match self {
+                Transport::Direct => Ok((Box::new(TcpStream::connect((host, port)).await?) as Box<dyn Stream>, None)),
+                Transport::HttpProxy(proxy) => Ok((http_connect(proxy, host, port).await?, None)),
+                Transport::Socks5(proxy) => {
+                    let url = Url::parse(proxy)?;
+                    let proxy_addr = (url.host_str().ok_or("Proxy URL has no host")?, url.port().unwrap_or(1080));
+                    let stream = if url.username().is_empty() {
+                        tokio_socks::tcp::Socks5Stream::connect(proxy_addr, (host, port)).await?
+                    } else {
+                        tokio_socks::tcp::Socks5Stream::connect_with_password(
+                            proxy_addr, (host, port), url.username(), url.password().unwrap_or("")
+                        ).await?
+                    };
+                    Ok((Box::new(stream.into_inner()) as Box<dyn Stream>, None))
+                }
+                Transport::Ssh(target) => {
+                    let tunnel = SshTunnel::open(target, host, port, timeout).await?;
+                    let stream = TcpStream::connect(("127.0.0.1", tunnel.local_port)).await?;
+                    Ok((Box::new(stream) as Box<dyn Stream>, Some(tunnel)))
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, connect).await
+            .map_err(|_| format!("Timed out connecting to {}:{}", host, port))?
+    }
+}
+
+// CONNECT through an HTTP proxy by hand. An https:// proxy is checked
+// against the system trust store, same as reqwest does for WHM calls.
+async fn http_connect(proxy: &str, host: &str, port: u16) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    let url = Url::parse(proxy)?;
+    let proxy_host = url.host_str().ok_or("Proxy URL has no host")?;
+    let stream = TcpStream::connect((proxy_host, url.port_or_known_default().unwrap_or(80))).await?;
+
+    match url.scheme() {
+        "https" => {
+            let connector = TlsConnector::from(Arc::new(tls::client_config(&TlsMode::System)?));
+            // Url keeps the brackets on IPv6 hosts, ServerName wants them off
+            let name = ServerName::try_from(proxy_host.trim_start_matches('[').trim_end_matches(']').to_string())?;
+            let stream = connector.connect(name, stream).await
+                .map_err(|e| format!("TLS to proxy {} failed: {}", proxy_host, e))?;
+            Ok(Box::new(connect_tunnel(stream, &url, host, port).await?))
+        }
+        _ => Ok(Box::new(connect_tunnel(stream, &url, host, port).await?))
+    }
+}
+
+async fn connect_tunnel<S: Stream>(mut stream: S, url: &Url, host: &str, port: u16) -> Result<S, Box<dyn Error>> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port)
+    };
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if !url.username().is_empty() {
+        let creds = format!("{}:{}", url.username(), url.password().unwrap_or(""));
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(creds)));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // Read the proxy's answer up to the blank line, byte by byte so nothing
+    // that belongs to the tunnel gets eaten.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 {
+            return Err("Proxy sent an oversized response to CONNECT".into())
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(format!("Proxy refused CONNECT to {}: {}", authority, head.lines().next().unwrap_or("")).into())
+    }
+
+    Ok(stream)
+}
+
+// Grab a port that's free right now for the local end of an ssh forward
+pub fn free_local_port() -> Result<u16, Box<dyn Error>> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+// `ssh -N -L` running in the background. The ssh process is killed when this
+// is dropped.
+pub struct SshTunnel {
+    pub local_port: u16,
+    _child: Child
+}
+
+impl SshTunnel {
+    pub async fn open(target: &str, host: &str, port: u16, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        SshTunnel::open_on(free_local_port()?, target, host, port, timeout).await
+    }
+
+    // Forward 127.0.0.1:local_port to host:port as seen from the ssh target
+    // and wait until the forward accepts connections.
+    pub async fn open_on(local_port: u16, target: &str, host: &str, port: u16, timeout: Duration)
+    -> Result<Self, Box<dyn Error>> {
+        let remote = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", host),
+            _ => host.to_string()
+        };
+        let forward = format!("127.0.0.1:{}:{}:{}", local_port, remote, port);
+
+        log::debug!("Opening ssh tunnel {} via {}", forward, target);
+        let mut child = Command::new("ssh")
+            .args(["-N", "-o", "ExitOnForwardFailure=yes", "-o", "BatchMode=yes", "-L", &forward])
+            .args(target.split_whitespace())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Unable to run ssh: {}", e))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Err(format!("ssh to {} exited with {} before the tunnel came up", target, status).into())
+            }
+            if TcpStream::connect(("127.0.0.1", local_port)).await.is_ok() {
+                return Ok(Self { local_port, _child: child })
+            }
+            if Instant::now() > deadline {
+                return Err(format!("Timed out waiting for ssh tunnel via {}", target).into())
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
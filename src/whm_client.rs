@@ -0,0 +1,452 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::{header, Client, ClientBuilder, Proxy, Response};
+use serde_json::Value;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::time::Instant;
+use url::Url;
+
+use crate::config::{Config, MAX_RETRIES_LIMIT, MIN_REQUESTS_PER_SECOND};
+use crate::error_types::WhmError;
+use crate::response_cache::ResponseCache;
+use crate::server_facts::DiskUsage;
+use crate::sqlite_types::{DomainRow, ServerRow};
+use crate::tls;
+use crate::transport::{self, SshTunnel, Transport};
+
+// Longest wait between two attempts, however many retries are allowed
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Request settings for one server: the Config values with whatever the
+// servers row overrides.
+#[derive(Debug, Clone)]
+pub struct WhmSettings {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    // 0 means no limit
+    pub requests_per_second: f64
+}
+
+impl WhmSettings {
+    pub fn new(config: &Config, server: &ServerRow) -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(server.connect_timeout_secs.unwrap_or(config.connect_timeout_secs())),
+            read_timeout: Duration::from_secs(server.read_timeout_secs.unwrap_or(config.read_timeout_secs())),
+            max_retries: server.max_retries.unwrap_or(config.max_retries()).min(MAX_RETRIES_LIMIT),
+            retry_backoff: Duration::from_millis(server.retry_backoff_ms.unwrap_or(config.retry_backoff_ms())),
+            requests_per_second: server.requests_per_second.unwrap_or(config.requests_per_second())
+        }
+    }
+}
+
+// Spaces out requests so a server never sees more than requests_per_second.
+// Every attempt goes through here, retries included.
+struct RateLimiter {
+    interval: Option<Duration>,
+    next: Mutex<Instant>
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> Self {
+        // Config and server add refuse anything slower, this only guards
+        // against a value put in the database by hand
+        let interval = if requests_per_second > 0.0 {
+            Some(Duration::from_secs_f64(1.0 / requests_per_second.max(MIN_REQUESTS_PER_SECOND)))
+        } else {
+            None
+        };
+
+        Self { interval, next: Mutex::new(Instant::now()) }
+    }
+
+    async fn wait(&self) {
+        let Some(interval) = self.interval else {
+            return
+        };
+
+        let mut next = self.next.lock().await;
+        let now = Instant::now();
+        if *next > now {
+            tokio::time::sleep_until(*next).await;
+        }
+        *next = Instant::now().max(*next) + interval;
+    }
+}
+
+// Where the ssh tunnel for a client should listen once it's opened
+struct TunnelSpec {
+    target: String,
+    local_port: u16
+}
+
+// Thin wrapper around reqwest for talking to whmapi1 on one server. Every
+// call goes through `call` which takes care of the auth header and unpacks
+// the metadata envelope so callers only ever see `data` or a WhmError.
+pub struct WhmClient {
+    server: ServerRow,
+    settings: WhmSettings,
+    limiter: RateLimiter,
+    client: Client,
+    // Same as client but with the hostname pinned to the stored IP. Only
+    // there when the server is reached by hostname.
+    fallback: Option<Client>,
+    base: Url,
+    tunnel: Option<TunnelSpec>,
+    tunnel_handle: OnceCell<SshTunnel>,
+    cache: Option<ResponseCache>,
+    auth: header::HeaderValue
+}
+
+impl WhmClient {
+    pub fn new(server: &ServerRow, config: &Config) -> Result<Self, WhmError> {
+        let settings = WhmSettings::new(config, server);
+        let host = server.connect_host();
+
+        let (client, fallback, base, tunnel) = match &server.transport {
+            // Everything goes to the local end of the tunnel. A hostname keeps
+            // its SNI and Host header and just resolves to 127.0.0.1, an IP
+            // has to be swapped out in the URL.
+            Transport::Ssh(target) => {
+                let local_port = transport::free_local_port()
+                    .map_err(|e| WhmError::Transport(format!("{}: {}", server.name, e)))?;
+                let builder = WhmClient::builder(server, &settings)?;
+                let (builder, base) = match host.parse::<IpAddr>() {
+                    Ok(_) => (builder, WhmClient::base_url(server, "127.0.0.1", local_port)?),
+                    Err(_) => (
+                        builder.resolve(host, SocketAddr::from(([127, 0, 0, 1], local_port))),
+                        WhmClient::base_url(server, host, local_port)?
+                    )
+                };
+                let tunnel = TunnelSpec { target: target.clone(), local_port };
+                (builder.build()?, None, base, Some(tunnel))
+            }
+            _ => {
+                let client = WhmClient::builder(server, &settings)?.build()?;
+                let fallback = match (&server.hostname, server.ip.parse::<IpAddr>()) {
+                    (Some(hostname), Ok(ip)) if server.use_hostname => Some(
+                        WhmClient::builder(server, &settings)?
+                            .resolve(hostname, SocketAddr::new(ip, server.port))
+                            .build()?
+                    ),
+                    _ => None
+                };
+                (client, fallback, WhmClient::base_url(server, host, server.port)?, None)
+            }
+        };
+        let auth = header::HeaderValue::from_str(&format!("whm {}:{}", server.user, server.apikey))
+            .map_err(|e| WhmError::Config(format!("Invalid API key for {}: {}", server.name, e)))?;
+
+        Ok(Self {
+            server: server.clone(),
+            limiter: RateLimiter::new(settings.requests_per_second),
+            settings,
+            client,
+            fallback,
+            base,
+            tunnel,
+            tunnel_handle: OnceCell::new(),
+            cache: None,
+            auth
+        })
+    }
+
+    // Keep a raw copy of every response in the cache
+    pub fn with_cache(mut self, cache: Option<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    fn builder(server: &ServerRow, settings: &WhmSettings) -> Result<ClientBuilder, WhmError> {
+        let tls = tls::client_config(&server.tls)
+            .map_err(|e| WhmError::Tls(format!("{}: {}", server.name, e)))?;
+        let builder = ClientBuilder::new()
+            .use_preconfigured_tls(tls)
+            .connect_timeout(settings.connect_timeout)
+            .read_timeout(settings.read_timeout);
+
+        match &server.transport {
+            Transport::HttpProxy(proxy) | Transport::Socks5(proxy) => Ok(builder.proxy(Proxy::all(proxy)?)),
+            _ => Ok(builder)
+        }
+    }
+
+    // scheme://host:port/base_path/json-api/
+    fn base_url(server: &ServerRow, host: &str, port: u16) -> Result<Url, WhmError> {
+        let host = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", host),
+            _ => host.to_string()
+        };
+        let base_path = match server.base_path.trim_matches('/') {
+            "" => String::new(),
+            p => format!("/{}", p)
+        };
+
+        Url::parse(&format!("{}://{}:{}{}/json-api/", server.scheme, host, port, base_path))
+            .map_err(|e| WhmError::Config(format!("Invalid URL for {}: {}", server.name, e)))
+    }
+
+    // Bring the ssh tunnel up on first use. It stays up until the client is
+    // dropped.
+    async fn ensure_tunnel(&self) -> Result<(), WhmError> {
+        let Some(spec) = &self.tunnel else {
+            return Ok(())
+        };
+
+        self.tunnel_handle.get_or_try_init(|| async {
+            SshTunnel::open_on(spec.local_port, &spec.target, self.server.connect_host(), self.server.port,
+                self.settings.connect_timeout).await
+                .map_err(|e| WhmError::Transport(format!("{}: {}", self.server.name, e)))
+        }).await?;
+
+        Ok(())
+    }
+
+    pub fn server(&self) -> &ServerRow {
+        &self.server
+    }
+
+    fn endpoint(&self, function: &str, params: &[(&str, &str)]) -> Result<Url, WhmError> {
+        let mut url = self.base.join(function)
+            .map_err(|e| WhmError::Config(format!("Invalid URL for {}: {}", self.server.name, e)))?;
+        url.query_pairs_mut()
+            .append_pair("api.version", "1")
+            .extend_pairs(params);
+
+        Ok(url)
+    }
+
+    async fn send(&self, client: &Client, url: Url) -> Result<Response, reqwest::Error> {
+        self.limiter.wait().await;
+        client.get(url)
+            .header(header::AUTHORIZATION, self.auth.clone())
+            .send()
+            .await
+    }
+
+    // One attempt, falling back to the IP if the hostname can't be reached
+    async fn send_once(&self, url: Url) -> Result<Response, WhmError> {
+        self.ensure_tunnel().await?;
+        let resp = match (self.send(&self.client, url.clone()).await, &self.fallback) {
+            (Err(e), Some(fallback)) if e.is_connect() => {
+                log::warn!("{}: unable to connect to {}, falling back to IP {}. {}",
+                    self.server.name, self.server.connect_host(), self.server.ip, e);
+                self.send(fallback, url).await?
+            }
+            (r, _) => r?
+        };
+        log::debug!("Got response {:?}", resp);
+
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(WhmError::Status(status))
+        }
+
+        Ok(resp)
+    }
+
+    // Retry 5xx and connection failures with exponential backoff. Anything
+    // else (auth, timeouts, TLS) won't get better by asking again.
+    async fn send_with_retries(&self, url: Url, max_retries: u32) -> Result<Response, WhmError> {
+        let mut attempt = 0;
+        loop {
+            match self.send_once(url.clone()).await {
+                Err(e) if attempt < max_retries && e.is_retryable() => {
+                    let backoff = self.settings.retry_backoff
+                        .saturating_mul(2u32.checked_pow(attempt).unwrap_or(u32::MAX))
+                        .min(MAX_BACKOFF);
+                    attempt += 1;
+                    log::warn!("{}: {}. Retrying in {:?} ({}/{})",
+                        self.server.name, e, backoff, attempt, max_retries);
+                    tokio::time::sleep(backoff).await;
+                }
+                r => return r
+            }
+        }
+    }
+
+    // Call a whmapi1 function and return its `data` member. Anything other
+    // than metadata.result == 1 is turned into WhmError::Api.
+    pub async fn call(&self, function: &str, params: &[(&str, &str)]) -> Result<Value, WhmError> {
+        self.request(function, params, self.settings.max_retries).await
+    }
+
+    // Same as call but never retried, for functions that aren't safe to send
+    // twice
+    pub async fn call_once(&self, function: &str, params: &[(&str, &str)]) -> Result<Value, WhmError> {
+        self.request(function, params, 0).await
+    }
+
+    async fn request(&self, function: &str, params: &[(&str, &str)], max_retries: u32) -> Result<Value, WhmError> {
+        let url = self.endpoint(function, params)?;
+        log::debug!("Sending {} to {} via {}", function, self.server.name, self.server.connect_host());
+
+        let resp = self.send_with_retries(url, max_retries).await?;
+        let raw = resp.bytes().await?;
+        if let Some(cache) = &self.cache {
+            // Losing a debug copy isn't worth failing the call over
+            if let Err(e) = cache.store(&self.server.name, function, &raw) {
+                log::warn!("{}: unable to cache {} response. {}", self.server.name, function, e);
+            }
+        }
+        let body = serde_json::from_slice::<Value>(&raw)
+            .map_err(|e| WhmError::Decode(e.to_string()))?;
+        log::debug!("Response data\n{:?}", body);
+
+        WhmClient::unpack(function, body)
+    }
+
+    pub fn unpack(function: &str, mut body: Value) -> Result<Value, WhmError> {
+        let metadata = body.get("metadata")
+            .ok_or_else(|| WhmError::Decode(format!("{} response has no metadata", function)))?;
+        let result = metadata["result"].as_i64()
+            .ok_or_else(|| WhmError::Decode(format!("{} response has no metadata.result", function)))?;
+
+        if result != 1 {
+            let reason = metadata["reason"].as_str().unwrap_or("no reason given");
+            return Err(WhmError::Api { function: function.to_string(), reason: reason.to_string() })
+        }
+
+        Ok(body["data"].take())
+    }
+
+    // whmapi1 version
+    pub async fn version(&self) -> Result<String, WhmError> {
+        let data = self.call("version", &[]).await?;
+
+        data["version"].as_str()
+            .map(|v| v.to_string())
+            .ok_or_else(|| WhmError::Decode("version response has no data.version".to_string()))
+    }
+
+    // whmapi1 myprivs. Names of the ACLs the token holds, `all` included.
+    pub async fn myprivs(&self) -> Result<Vec<String>, WhmError> {
+        let data = self.call("myprivs", &[]).await?;
+        let privileges = data["privileges"][0].as_object()
+            .ok_or_else(|| WhmError::Decode("myprivs response has no data.privileges".to_string()))?;
+
+        Ok(privileges.iter()
+            .filter(|(_, v)| v.as_i64() == Some(1) || v.as_bool() == Some(true))
+            .map(|(acl, _)| acl.clone())
+            .collect())
+    }
+
+    // whmapi1 gethostname. The hostname WHM itself goes by.
+    pub async fn hostname(&self) -> Result<String, WhmError> {
+        let data = self.call("gethostname", &[]).await?;
+
+        data["hostname"].as_str()
+            .map(|h| h.to_string())
+            .ok_or_else(|| WhmError::Decode("gethostname response has no data.hostname".to_string()))
+    }
+
+    // whmapi1 systemloadavg. 1, 5 and 15 minute load averages.
+    pub async fn load_average(&self) -> Result<(f64, f64, f64), WhmError> {
+        let data = self.call("systemloadavg", &[]).await?;
+        // Numbers on some versions, strings on others
+        let load = |k: &str| data[k].as_f64().or_else(|| data[k].as_str().and_then(|s| s.trim().parse().ok()))
+            .ok_or_else(|| WhmError::Decode(format!("systemloadavg response has no data.{}", k)));
+
+        Ok((load("one")?, load("five")?, load("fifteen")?))
+    }
+
+    // whmapi1 getdiskusage. One entry per mounted partition.
+    pub async fn disk_usage(&self) -> Result<Vec<DiskUsage>, WhmError> {
+        let data = self.call("getdiskusage", &[]).await?;
+
+        serde_json::from_value(data["partition"].clone())
+            .map_err(|e| WhmError::Decode(format!("getdiskusage response has a bad data.partition: {}", e)))
+    }
+
+    // whmapi1 get_current_users_count. Number of cPanel accounts.
+    pub async fn account_count(&self) -> Result<i64, WhmError> {
+        let data = self.call("get_current_users_count", &[]).await?;
+
+        data["users"].as_i64()
+            .ok_or_else(|| WhmError::Decode("get_current_users_count response has no data.users".to_string()))
+    }
+
+    // whmapi1 get_update_config. The update tier, e.g. release or lts.
+    pub async fn tier(&self) -> Result<String, WhmError> {
+        let data = self.call("get_update_config", &[]).await?;
+
+        data["CPANEL"].as_str()
+            .map(|t| t.to_string())
+            .ok_or_else(|| WhmError::Decode("get_update_config response has no data.CPANEL".to_string()))
+    }
+
+    // whmapi1 get_os_info. Distribution name and release.
+    pub async fn os_release(&self) -> Result<String, WhmError> {
+        let data = self.call("get_os_info", &[]).await?;
+
+        match (data["name"].as_str(), data["version"].as_str()) {
+            (Some(name), Some(version)) => Ok(format!("{} {}", name, version)),
+            _ => Err(WhmError::Decode("get_os_info response has no data.name and data.version".to_string()))
+        }
+    }
+
+    // whmapi1 api_token_create. Returns the new token itself, which WHM
+    // never shows again. Don't call this on a client with a response cache.
+    pub async fn api_token_create(&self, name: &str, acls: &[String]) -> Result<String, WhmError> {
+        let keys: Vec<String> = (1..=acls.len()).map(|i| format!("acl-{}", i)).collect();
+        let mut params = vec![("token_name", name)];
+        params.extend(keys.iter().map(|k| k.as_str()).zip(acls.iter().map(|a| a.as_str())));
+        // A retry after a lost response would leave a second token behind
+        let data = self.call_once("api_token_create", &params).await?;
+
+        data["token"].as_str()
+            .map(|t| t.to_string())
+            .ok_or_else(|| WhmError::Decode("api_token_create response has no data.token".to_string()))
+    }
+
+    // whmapi1 api_token_list. Names of the tokens the user has.
+    pub async fn api_token_list(&self) -> Result<Vec<String>, WhmError> {
+        let data = self.call("api_token_list", &[]).await?;
+        let tokens = data["tokens"].as_object()
+            .ok_or_else(|| WhmError::Decode("api_token_list response has no data.tokens".to_string()))?;
+
+        Ok(tokens.keys().cloned().collect())
+    }
+
+    // whmapi1 api_token_revoke
+    pub async fn api_token_revoke(&self, name: &str) -> Result<(), WhmError> {
+        self.call("api_token_revoke", &[("token_name", name)]).await?;
+
+        Ok(())
+    }
+
+    // whmapi1 get_domain_info
+    pub async fn get_domain_info(&self) -> Result<Vec<DomainRow>, WhmError> {
+        let data = self.call("get_domain_info", &[]).await?;
+
+        Ok(WhmClient::parse_domain_info(&data))
+    }
+
+    // Rows that don't deserialize are logged and dropped so one odd domain
+    // doesn't throw away the whole server.
+    pub fn parse_domain_info(data: &Value) -> Vec<DomainRow> {
+        let (rows, rejected) = WhmClient::split_domain_info(data);
+        for (_, e) in rejected {
+            log::debug!("Unable to convert row! {}", e);
+        }
+
+        rows
+    }
+
+    // The rows that deserialize, and the raw rows that don't with the reason
+    pub fn split_domain_info(data: &Value) -> (Vec<DomainRow>, Vec<(Value, serde_json::Error)>) {
+        let mut rows = Vec::new();
+        let mut rejected = Vec::new();
+        for x in data["domains"].as_array().into_iter().flatten() {
+            log::debug!("{:?}", x);
+            match serde_json::from_value::<DomainRow>(x.clone()) {
+                Ok(v) => rows.push(v),
+                Err(e) => rejected.push((x.clone(), e))
+            }
+        }
+
+        (rows, rejected)
+    }
+}
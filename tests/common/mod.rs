@@ -0,0 +1,436 @@
+// Shared bits for the integration tests: a tiny whmapi1 server that serves
+// canned fixtures, and a wrapper that runs the cpcm binary against its own
+// temporary CPCM_DATA_DIR.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const APIKEY: &str = "GOODTOKEN";
+
+pub fn fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Unable to read fixture {}: {}", path.display(), e))
+}
+
+#[derive(Clone)]
+pub struct Route {
+    pub status: u16,
+    pub body: String,
+    pub delay: Duration
+}
+
+impl Route {
+    pub fn ok(body: String) -> Self {
+        Self { status: 200, body, delay: Duration::ZERO }
+    }
+
+    pub fn status(status: u16, body: String) -> Self {
+        Self { status, body, delay: Duration::ZERO }
+    }
+
+    pub fn slow(body: String, delay: Duration) -> Self {
+        Self { status: 200, body, delay }
+    }
+}
+
+// Plain HTTP stand-in for whmapi1. Routes are keyed by function name, i.e.
+// the last path segment of /json-api/<function>. Requests without a
+// `whm root:<token>` authorization header for an accepted token, APIKEY to
+// begin with, get a 403 like WHM does.
+pub struct MockWhm {
+    pub port: u16,
+    routes: Arc<Mutex<HashMap<String, Route>>>,
+    hits: Arc<AtomicUsize>,
+    tokens: Arc<Mutex<HashSet<String>>>,
+    // Path and query of every request, in order
+    requests: Arc<Mutex<Vec<String>>>,
+    // Host header of every request, in order
+    hosts: Arc<Mutex<Vec<String>>>
+}
+
+impl MockWhm {
+    pub fn start() -> Self {
+        MockWhm::listen(None)
+    }
+
+    // Same mock behind TLS, with the certificate from tests/fixtures/tls
+    pub fn start_tls() -> Self {
+        MockWhm::listen(Some(server_tls_config()))
+    }
+
+    fn listen(tls: Option<Arc<ServerConfig>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let routes: Arc<Mutex<HashMap<String, Route>>> = Arc::new(Mutex::new(HashMap::new()));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let tokens = Arc::new(Mutex::new(HashSet::from([APIKEY.to_string()])));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let hosts = Arc::new(Mutex::new(Vec::new()));
+
+        let mock = Self { port, routes, hits, tokens, requests, hosts };
+        let shared = mock.shared();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = shared.shared();
+                let tls = tls.clone();
+                thread::spawn(move || match tls {
+                    Some(config) => shared.handle(rustls::StreamOwned::new(
+                        rustls::ServerConnection::new(config).unwrap(), stream)),
+                    None => shared.handle(stream)
+                });
+            }
+        });
+
+        mock
+    }
+
+    pub fn route(self, function: &str, route: Route) -> Self {
+        self.routes.lock().unwrap().insert(function.to_string(), route);
+        self
+    }
+
+    pub fn set_route(&self, function: &str, route: Route) {
+        self.routes.lock().unwrap().insert(function.to_string(), route);
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    pub fn accept_token(&self, token: &str) {
+        self.tokens.lock().unwrap().insert(token.to_string());
+    }
+
+    // Requests made to one function
+    pub fn requests(&self, function: &str) -> Vec<String> {
+        self.requests.lock().unwrap().iter()
+            .filter(|r| r.split('?').next().unwrap_or("").ends_with(&format!("/{}", function)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn hosts(&self) -> Vec<String> {
+        self.hosts.lock().unwrap().clone()
+    }
+
+    // Another handle on the same state for the listener threads
+    fn shared(&self) -> Self {
+        Self {
+            port: self.port,
+            routes: self.routes.clone(),
+            hits: self.hits.clone(),
+            tokens: self.tokens.clone(),
+            requests: self.requests.clone(),
+            hosts: self.hosts.clone()
+        }
+    }
+
+    fn handle<S: Read + Write>(&self, stream: S) {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).is_err() {
+            return
+        }
+
+        let mut authorized = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                break
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                let token = v.trim().strip_prefix("whm root:").unwrap_or("");
+                if k.eq_ignore_ascii_case("authorization") && self.tokens.lock().unwrap().contains(token) {
+                    authorized = true;
+                }
+                if k.eq_ignore_ascii_case("host") {
+                    self.hosts.lock().unwrap().push(v.trim().to_string());
+                }
+            }
+        }
+        self.hits.fetch_add(1, Ordering::SeqCst);
+
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+        self.requests.lock().unwrap().push(path.to_string());
+        let function = path.split('?').next().unwrap_or("").rsplit('/').next().unwrap_or("");
+        let route = match (authorized, self.routes.lock().unwrap().get(function)) {
+            (false, _) => Route::status(403, "Access denied".to_string()),
+            (true, Some(r)) => r.clone(),
+            (true, None) => Route::status(404, "Not found".to_string())
+        };
+
+        thread::sleep(route.delay);
+        let stream = reader.get_mut();
+        let _ = write!(stream,
+            "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            route.status, route.body.len(), route.body);
+        let _ = stream.flush();
+    }
+}
+
+pub fn tls_fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+}
+
+// server.pem is for 127.0.0.1 and localhost, signed by ca.pem
+fn server_tls_config() -> Arc<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(tls_fixture("server.pem")).unwrap()
+        .collect::<Result<Vec<_>, _>>().unwrap();
+    let key = PrivateKeyDer::from_pem_file(tls_fixture("server.key")).unwrap();
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions().unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key).unwrap();
+
+    Arc::new(config)
+}
+
+// SHA-256 of server.pem, in the form cpcm stores it
+pub fn server_fingerprint() -> String {
+    let cert = CertificateDer::from_pem_file(tls_fixture("server.pem")).unwrap();
+    Sha256::digest(&cert).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Runs a tokio accept loop on its own thread, for the proxies below
+fn serve<F, Fut>(handler: F) -> u16
+where
+    F: Fn(tokio::net::TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    listener.set_nonblocking(true).unwrap();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handler(stream));
+            }
+        });
+    });
+
+    port
+}
+
+// HTTP CONNECT proxy, optionally spoken to over TLS with the same
+// certificate as the TLS mock. Remembers every host:port it was asked for.
+pub struct ConnectProxy {
+    pub port: u16,
+    targets: Arc<Mutex<Vec<String>>>
+}
+
+impl ConnectProxy {
+    pub fn start() -> Self {
+        ConnectProxy::listen(false)
+    }
+
+    pub fn start_tls() -> Self {
+        ConnectProxy::listen(true)
+    }
+
+    fn listen(tls: bool) -> Self {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let acceptor = tls.then(|| tokio_rustls::TlsAcceptor::from(server_tls_config()));
+        let shared = targets.clone();
+        let port = serve(move |stream| {
+            let (acceptor, targets) = (acceptor.clone(), shared.clone());
+            async move {
+                match acceptor {
+                    Some(acceptor) => if let Ok(stream) = acceptor.accept(stream).await {
+                        connect_tunnel(stream, targets).await
+                    },
+                    None => connect_tunnel(stream, targets).await
+                }
+            }
+        });
+
+        Self { port, targets }
+    }
+
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+async fn connect_tunnel<S: AsyncRead + AsyncWrite + Unpin>(mut client: S, targets: Arc<Mutex<Vec<String>>>) {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        match client.read_u8().await {
+            Ok(b) => head.push(b),
+            Err(_) => return
+        }
+    }
+    let target = String::from_utf8_lossy(&head).split_whitespace().nth(1).unwrap_or("").to_string();
+    targets.lock().unwrap().push(target.clone());
+
+    let Ok(mut upstream) = tokio::net::TcpStream::connect(&target).await else {
+        let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        return
+    };
+    if client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.is_ok() {
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    }
+}
+
+// SOCKS5 proxy without authentication. Remembers every host:port it was
+// asked for, as the client sent it, so socks5h hostnames show up unresolved.
+pub struct Socks5Proxy {
+    pub port: u16,
+    targets: Arc<Mutex<Vec<String>>>
+}
+
+impl Socks5Proxy {
+    pub fn start() -> Self {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let shared = targets.clone();
+        let port = serve(move |stream| socks5_tunnel(stream, shared.clone()));
+
+        Self { port, targets }
+    }
+
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+async fn socks5_tunnel(mut client: tokio::net::TcpStream, targets: Arc<Mutex<Vec<String>>>) {
+    let mut greeting = [0u8; 2];
+    if client.read_exact(&mut greeting).await.is_err() {
+        return
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    if client.read_exact(&mut methods).await.is_err() || client.write_all(&[5, 0]).await.is_err() {
+        return
+    }
+
+    // VER CMD RSV ATYP, then the address and port
+    let mut request = [0u8; 4];
+    if client.read_exact(&mut request).await.is_err() {
+        return
+    }
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            if client.read_exact(&mut ip).await.is_err() {
+                return
+            }
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let Ok(len) = client.read_u8().await else {
+                return
+            };
+            let mut name = vec![0u8; len as usize];
+            if client.read_exact(&mut name).await.is_err() {
+                return
+            }
+            String::from_utf8_lossy(&name).to_string()
+        }
+        _ => return
+    };
+    let Ok(port) = client.read_u16().await else {
+        return
+    };
+    let target = format!("{}:{}", host, port);
+    targets.lock().unwrap().push(target.clone());
+
+    let Ok(mut upstream) = tokio::net::TcpStream::connect(&target).await else {
+        let _ = client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+        return
+    };
+    if client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.is_ok() {
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    }
+}
+
+// Runs the cpcm binary against a fresh data directory
+pub struct Cpcm {
+    pub datadir: TempDir
+}
+
+impl Cpcm {
+    pub fn init() -> Self {
+        let cpcm = Self { datadir: tempfile::tempdir().unwrap() };
+        let out = cpcm.run(&["init"]);
+        assert!(out.status.success(), "cpcm init failed: {}", String::from_utf8_lossy(&out.stderr));
+        cpcm
+    }
+
+    // Set one key in config.json
+    pub fn set_config(&self, key: &str, value: serde_json::Value) {
+        let path = self.datadir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        config[key] = value;
+        std::fs::write(&path, config.to_string()).unwrap();
+    }
+
+    pub fn dbfile(&self) -> PathBuf {
+        self.datadir.path().join("cpcm.db")
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.run_with_stdin(args, "")
+    }
+
+    pub fn run_with_stdin(&self, args: &[&str], stdin: &str) -> Output {
+        self.run_with_env(args, stdin, &[])
+    }
+
+    pub fn run_with_env(&self, args: &[&str], stdin: &str, env: &[(&str, &str)]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cpcm"))
+            .args(args)
+            .env("CPCM_DATA_DIR", self.datadir.path())
+            .env_remove("RUST_LOG")
+            .env_remove("HTTP_PROXY")
+            .env_remove("HTTPS_PROXY")
+            .env_remove("ALL_PROXY")
+            .env_remove("http_proxy")
+            .env_remove("https_proxy")
+            .env_remove("all_proxy")
+            .env_remove("SSL_CERT_FILE")
+            .env_remove("SSL_CERT_DIR")
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+
+        child.wait_with_output().unwrap()
+    }
+
+    // server add pointed at a mock, key fed in on stdin. Skips the test that
+    // add runs so mocks only see the calls a test makes itself.
+    pub fn add_server(&self, name: &str, mock: &MockWhm, extra: &[&str]) -> Output {
+        let port = mock.port.to_string();
+        let mut args = vec!["server", "add", "--name", name, "--ip", "127.0.0.1", "--user", "root",
+            "--scheme", "http", "--port", &port, "--no-test"];
+        args.extend_from_slice(extra);
+
+        let out = self.run_with_stdin(&args, &format!("{}\n", APIKEY));
+        assert!(out.status.success(), "server add failed: {}", String::from_utf8_lossy(&out.stderr));
+        out
+    }
+}
+
+pub fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).to_string()
+}
@@ -0,0 +1,605 @@
+mod common;
+
+use common::{fixture, server_fingerprint, stdout, tls_fixture, ConnectProxy, Cpcm, MockWhm, Route, APIKEY};
+
+#[test]
+fn list_servers_in_every_format() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &["--group", "prod", "--hostname", "web01.example.com"]);
+    cpcm.add_server("web02", &whm, &["--group", "staging"]);
+    assert!(cpcm.run(&["domain", "--sync", "--server", "web01"]).status.success());
+
+    let table = stdout(&cpcm.run(&["server", "list"]));
+    assert!(table.contains("web01.example.com"), "{}", table);
+    assert!(table.lines().any(|l| l.contains("web02") && l.contains("never")), "{}", table);
+
+    let out = cpcm.run(&["server", "list", "--output", "json"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let json: serde_json::Value = serde_json::from_str(&stdout(&out)).unwrap();
+    assert_eq!(json[0]["name"], "web01");
+    assert_eq!(json[0]["domains"], 3);
+    assert!(json[0]["last_sync"].is_string());
+    assert_eq!(json[1]["domains"], 0);
+    assert!(json[1]["last_sync"].is_null());
+    assert!(json[1]["hostname"].is_null());
+
+    let csv = stdout(&cpcm.run(&["server", "list", "-o", "csv", "--group", "staging"]));
+    assert_eq!(csv.lines().next(), Some("name,ip,hostname,group,domains,last_sync"));
+    assert_eq!(csv.lines().count(), 2, "{}", csv);
+    assert!(csv.contains("web02,127.0.0.1,,staging,0,"), "{}", csv);
+
+    for format in ["table", "json", "csv"] {
+        assert!(!stdout(&cpcm.run(&["server", "list", "-o", format])).contains(APIKEY));
+    }
+}
+
+#[test]
+fn remove_server_and_everything_about_it() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let other = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_malformed.json")));
+    let cpcm = Cpcm::init();
+    cpcm.set_config("cache_responses", true.into());
+    cpcm.add_server("web01", &whm, &[]);
+    cpcm.add_server("web02", &other, &[]);
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    assert!(!cpcm.run_with_stdin(&["server", "remove", "web01"], "n\n").status.success());
+    assert!(stdout(&cpcm.run(&["domain", "--name", "alpha"])).contains("alpha.example.com"));
+
+    let out = cpcm.run_with_stdin(&["server", "remove", "web01"], "y\n");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!stdout(&cpcm.run(&["server", "list"])).contains("web01"));
+    assert!(!stdout(&cpcm.run(&["domain", "--name", "example"])).contains("alpha.example.com"));
+    assert!(!stdout(&cpcm.run(&["changes"])).contains("web01"));
+    assert!(!cpcm.datadir.path().join("responses/web01").exists());
+
+    assert!(cpcm.run(&["server", "remove", "--yes", "--keep-history", "web02"]).status.success());
+    assert!(stdout(&cpcm.run(&["changes"])).contains("charlie.example.net"));
+    assert!(!stdout(&cpcm.run(&["domain", "--name", "charlie"])).contains("charlie.example.net"));
+
+    assert!(!cpcm.run(&["server", "remove", "-y", "web01"]).status.success());
+}
+
+#[test]
+fn edit_renames_and_moves_domains() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &["--group", "old"]);
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    let out = cpcm.run(&["server", "edit", "web01", "--rename", "web09", "--ip", "10.0.0.5", "--group", ""]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let json: serde_json::Value = serde_json::from_str(&stdout(&cpcm.run(&["server", "list", "-o", "json"]))).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["name"], "web09");
+    assert_eq!(json[0]["ip"], "10.0.0.5");
+    assert!(json[0]["group"].is_null());
+    assert_eq!(json[0]["domains"], 3);
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let moved: i64 = db.query_row(
+        "SELECT COUNT(*) FROM domains WHERE server_name = 'web09' AND server_ip = '10.0.0.5'", [], |r| r.get(0)
+    ).unwrap();
+    assert_eq!(moved, 3);
+    assert!(stdout(&cpcm.run(&["changes", "--server", "web09"])).contains("bravo.example.org"));
+
+    // Adding it again under a new IP would have left a duplicate behind
+    let port = whm.port.to_string();
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "web09", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port], "GOODTOKEN\n");
+    assert!(!out.status.success());
+
+    let out = cpcm.run_with_stdin(&["server", "edit", "web09", "--ip", "127.0.0.1", "--apikey"], "WRONGTOKEN\n");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!cpcm.run(&["domain", "--sync"]).status.success());
+    cpcm.run_with_stdin(&["server", "edit", "web09", "--apikey"], "GOODTOKEN\n");
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    assert!(!cpcm.run(&["server", "edit", "nosuchserver", "--ip", "10.0.0.6"]).status.success());
+}
+
+#[test]
+fn test_reports_version_and_missing_acls() {
+    let whm = MockWhm::start()
+        .route("version", Route::ok(fixture("version_ok.json")))
+        .route("myprivs", Route::ok(fixture("myprivs_limited.json")));
+    let down = MockWhm::start();
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &["--group", "prod"]);
+    cpcm.add_server("web02", &down, &[]);
+
+    let out = cpcm.run(&["server", "test", "web01"]);
+    assert!(!out.status.success());
+    let report = stdout(&out);
+    assert!(report.contains("11.120.0.5"), "{}", report);
+    assert!(report.contains("missing list-accts (domain sync)"), "{}", report);
+    assert!(report.contains("manage-api-tokens (rotate-token)"), "{}", report);
+    assert!(report.contains("basic-system-info (server facts)"), "{}", report);
+    assert!(report.contains("all (server facts tier)"), "{}", report);
+
+    whm.set_route("myprivs", Route::ok(fixture("myprivs_all.json")));
+    let out = cpcm.run(&["server", "test", "--group", "prod"]);
+    assert!(out.status.success(), "{}", stdout(&out));
+
+    let report = stdout(&cpcm.run(&["server", "test", "--all"]));
+    assert!(report.lines().any(|l| l.contains("web01") && l.contains("ok")), "{}", report);
+    assert!(report.lines().any(|l| l.contains("web02") && l.contains("404")), "{}", report);
+
+    assert!(!cpcm.run(&["server", "test"]).status.success());
+}
+
+#[test]
+fn add_tests_the_server_unless_skipped() {
+    let whm = MockWhm::start()
+        .route("version", Route::ok(fixture("version_ok.json")))
+        .route("myprivs", Route::ok(fixture("myprivs_all.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let add = ["server", "add", "--ip", "127.0.0.1", "--user", "root", "--scheme", "http", "--port", &port];
+
+    let out = cpcm.run_with_stdin(&[&add[..], &["--name", "good"]].concat(), "GOODTOKEN\n");
+    assert!(out.status.success());
+    assert!(stdout(&out).contains("11.120.0.5"));
+    assert_eq!(whm.hits(), 2);
+
+    let out = cpcm.run_with_stdin(&[&add[..], &["--name", "badkey"]].concat(), "WRONGTOKEN\n");
+    assert!(out.status.success());
+    assert!(stdout(&out).contains("auth failure"), "{}", stdout(&out));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("failed its test"));
+
+    let hits = whm.hits();
+    let out = cpcm.run_with_stdin(&[&add[..], &["--name", "skipped", "--no-test"]].concat(), "GOODTOKEN\n");
+    assert!(out.status.success());
+    assert_eq!(whm.hits(), hits);
+}
+
+#[test]
+fn import_inventory_and_report_skipped_rows() {
+    let cpcm = Cpcm::init();
+    let keyfile = cpcm.datadir.path().join("web02.key");
+    std::fs::write(&keyfile, "FILEKEY\n").unwrap();
+    let inventory = cpcm.datadir.path().join("servers.csv");
+    std::fs::write(&inventory, format!("\
+name,ip,user,hostname,group,apikey
+web01,127.0.0.1,root,web01.example.com,prod,env:CPCM_TEST_KEY
+web02,127.0.0.2,root,,prod,file:{}
+web03,not-an-ip,root,,,env:CPCM_TEST_KEY
+web04,127.0.0.4,root,,,env:CPCM_TEST_MISSING
+web05,127.0.0.5,root,,,prompt
+web01,127.0.0.6,root,,,prompt
+web06,127.0.0.7,root,,,hunter2
+", keyfile.display())).unwrap();
+
+    let out = cpcm.run_with_env(&["server", "import", inventory.to_str().unwrap()], "PROMPTKEY\n",
+        &[("CPCM_TEST_KEY", "ENVKEY")]);
+    assert!(!out.status.success());
+    let report = stdout(&out);
+    assert!(report.contains("Imported 3 of 7 servers"), "{}", report);
+    assert!(report.contains("not-an-ip is not an IP address"), "{}", report);
+    assert!(report.contains("CPCM_TEST_MISSING is not set"), "{}", report);
+    assert!(report.contains("web01 appears more than once"), "{}", report);
+    assert!(report.contains("apikey must be env:NAME, file:PATH or prompt"), "{}", report);
+    assert!(!report.contains("hunter2"), "{}", report);
+
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let key = |name: &str| db.query_row("SELECT apikey FROM servers WHERE name = ?1", [name], |r| r.get::<_, String>(0));
+    assert_eq!(key("web01").unwrap(), "ENVKEY");
+    assert_eq!(key("web02").unwrap(), "FILEKEY");
+    assert_eq!(key("web05").unwrap(), "PROMPTKEY");
+    assert!(key("web04").is_err());
+
+    let yaml = cpcm.datadir.path().join("more.yml");
+    std::fs::write(&yaml, "\
+- name: web07
+  ip: 127.0.0.8
+  user: root
+  group: staging
+  apikey: env:CPCM_TEST_KEY
+- name: web08
+  ip: 127.0.0.9
+  user: root
+  port: 2087
+  apikey: env:CPCM_TEST_KEY
+- name: web01
+  ip: 10.1.1.1
+  user: root
+  apikey: env:CPCM_TEST_KEY
+").unwrap();
+    let out = cpcm.run_with_env(&["server", "import", yaml.to_str().unwrap()], "", &[("CPCM_TEST_KEY", "ENVKEY")]);
+    let report = stdout(&out);
+    assert!(report.contains("Imported 1 of 3 servers"), "{}", report);
+    assert!(report.contains("unknown field `port`"), "{}", report);
+    assert!(report.contains("already exists with IP 127.0.0.1"), "{}", report);
+    assert!(stdout(&cpcm.run(&["server", "list", "--group", "staging"])).contains("web07"));
+}
+
+#[test]
+fn reimport_keeps_settings_the_inventory_doesnt_have() {
+    let cpcm = Cpcm::init();
+    let fingerprint = ["ab"; 32].join(":");
+    let out = cpcm.run_with_env(&["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+        "--hostname", "web01.example.com", "--use-hostname", "--tls-fingerprint", &fingerprint,
+        "--port", "8443", "--base-path", "/whm", "--read-timeout-secs", "90", "--proxy", "http://127.0.0.1:3128",
+        "--apikey-env", "CPCM_TEST_KEY", "--no-test"], "", &[("CPCM_TEST_KEY", "ENVKEY")]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    db.execute("UPDATE servers SET token_name = 'cpcm-1' WHERE name = 'web01'", []).unwrap();
+    let settings = || db.query_row("SELECT tls_mode, tls_fingerprint, port, base_path, read_timeout_secs, \
+        transport, transport_target, use_hostname FROM servers WHERE name = 'web01'", [], |r| Ok((
+            r.get::<_, String>(0)?, r.get::<_, Option<String>>(1)?, r.get::<_, u16>(2)?, r.get::<_, String>(3)?,
+            r.get::<_, Option<u64>>(4)?, r.get::<_, String>(5)?, r.get::<_, Option<String>>(6)?, r.get::<_, bool>(7)?
+        ))).unwrap();
+    let stored = |column: &str| db.query_row(&format!("SELECT `{}` FROM servers WHERE name = 'web01'", column), [],
+        |r| r.get::<_, Option<String>>(0)).unwrap();
+    let before = settings();
+
+    let inventory = cpcm.datadir.path().join("servers.csv");
+    std::fs::write(&inventory, "\
+name,ip,user,hostname,group,apikey
+web01,127.0.0.1,root,web01.example.com,prod,env:CPCM_TEST_KEY
+").unwrap();
+    let import = |key: &str| cpcm.run_with_env(&["server", "import", inventory.to_str().unwrap()], "",
+        &[("CPCM_TEST_KEY", key)]);
+
+    // Same key, so only the group is new. The token name stays too.
+    assert!(import("ENVKEY").status.success());
+    assert_eq!(settings(), before);
+    assert_eq!(stored("group").as_deref(), Some("prod"));
+    assert_eq!(stored("apikey").as_deref(), Some("ENVKEY"));
+    assert_eq!(stored("token_name").as_deref(), Some("cpcm-1"));
+
+    // A new key replaces the old one and the token name that went with it
+    assert!(import("OTHERKEY").status.success());
+    assert_eq!(settings(), before);
+    assert_eq!(stored("apikey").as_deref(), Some("OTHERKEY"));
+    assert_eq!(stored("token_name"), None);
+}
+
+#[test]
+fn apikey_from_env_file_or_stdin() {
+    let cpcm = Cpcm::init();
+    let add = |name: &str, ip: &str, extra: &[&str], stdin: &str| {
+        let mut args = vec!["server", "add", "--name", name, "--ip", ip, "--user", "root", "--no-test"];
+        args.extend_from_slice(extra);
+        cpcm.run_with_env(&args, stdin, &[("CPCM_TEST_KEY", "ENVKEY\n"), ("CPCM_TEST_EMPTY", " ")])
+    };
+    let keyfile = cpcm.datadir.path().join("key");
+    std::fs::write(&keyfile, "  FILEKEY\n").unwrap();
+
+    assert!(add("env", "127.0.0.1", &["--apikey-env", "CPCM_TEST_KEY"], "").status.success());
+    assert!(add("file", "127.0.0.2", &["--apikey-file", keyfile.to_str().unwrap()], "").status.success());
+    assert!(add("stdin", "127.0.0.3", &["--apikey-stdin"], "STDINKEY\n").status.success());
+
+    let out = add("empty", "127.0.0.4", &["--apikey-env", "CPCM_TEST_EMPTY"], "");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("the API key is empty"));
+    assert!(!add("unset", "127.0.0.5", &["--apikey-env", "CPCM_TEST_UNSET"], "").status.success());
+    assert!(!add("both", "127.0.0.6", &["--apikey-stdin", "--apikey-env", "CPCM_TEST_KEY"], "KEY\n").status.success());
+
+    let out = cpcm.run_with_env(&["server", "edit", "stdin", "--apikey-env", "CPCM_TEST_KEY"], "",
+        &[("CPCM_TEST_KEY", "ROTATED")]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let key = |name: &str| db.query_row("SELECT apikey FROM servers WHERE name = ?1", [name], |r| r.get::<_, String>(0));
+    assert_eq!(key("env").unwrap(), "ENVKEY");
+    assert_eq!(key("file").unwrap(), "FILEKEY");
+    assert_eq!(key("stdin").unwrap(), "ROTATED");
+    assert!(key("empty").is_err());
+    assert!(key("unset").is_err());
+    assert!(key("both").is_err());
+}
+
+#[test]
+fn encrypt_keys_with_a_passphrase() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+    let runtime = cpcm.datadir.path().to_str().unwrap().to_string();
+    let run = |args: &[&str], env: &[(&str, &str)]| {
+        let mut env = env.to_vec();
+        env.push(("XDG_RUNTIME_DIR", &runtime));
+        cpcm.run_with_env(args, "", &env)
+    };
+    let sync = |env: &[(&str, &str)]| run(&["domain", "--sync"], env).status.success();
+
+    let out = run(&["secrets", "migrate"], &[("CPCM_NEW_PASSPHRASE", "hunter2")]);
+    assert!(stdout(&out).contains("Encrypted 1 API keys"), "{}", String::from_utf8_lossy(&out.stderr));
+    let stored = |name: &str| rusqlite::Connection::open(cpcm.dbfile()).unwrap()
+        .query_row("SELECT apikey FROM servers WHERE name = ?1", [name], |r| r.get::<_, String>(0)).unwrap();
+    assert!(stored("web01").starts_with("enc:v1:"));
+    assert!(!stored("web01").contains(APIKEY));
+
+    assert!(sync(&[("CPCM_PASSPHRASE", "hunter2")]));
+    assert!(!sync(&[]));
+    assert!(!sync(&[("CPCM_PASSPHRASE", "wrong")]));
+
+    // Servers added after the migration are stored encrypted too
+    let port = whm.port.to_string();
+    let out = cpcm.run_with_env(&["server", "add", "--name", "web02", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port, "--no-test"], &format!("{}\n", APIKEY),
+        &[("CPCM_PASSPHRASE", "hunter2"), ("XDG_RUNTIME_DIR", &runtime)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stored("web02").starts_with("enc:v1:"));
+
+    assert!(run(&["secrets", "rekey"], &[("CPCM_PASSPHRASE", "hunter2"), ("CPCM_NEW_PASSPHRASE", "correct horse")])
+        .status.success());
+    assert!(!sync(&[("CPCM_PASSPHRASE", "hunter2")]));
+    assert!(sync(&[("CPCM_PASSPHRASE", "correct horse")]));
+
+    assert!(!run(&["secrets", "unlock"], &[("CPCM_PASSPHRASE", "hunter2")]).status.success());
+    // No runtime directory means no cached unlock rather than one in /tmp
+    let out = cpcm.run_with_env(&["secrets", "unlock"], "",
+        &[("CPCM_PASSPHRASE", "correct horse"), ("XDG_RUNTIME_DIR", "")]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("XDG_RUNTIME_DIR"));
+    assert!(run(&["secrets", "unlock", "--ttl", "1h"], &[("CPCM_PASSPHRASE", "correct horse")]).status.success());
+    assert!(sync(&[]));
+    assert!(run(&["secrets", "lock"], &[]).status.success());
+    assert!(!sync(&[]));
+}
+
+#[test]
+fn rotate_token_and_resume_after_a_failed_revoke() {
+    let whm = MockWhm::start()
+        .route("myprivs", Route::ok(fixture("myprivs_all.json")))
+        .route("api_token_create", Route::ok(fixture("api_token_create_ok.json")))
+        .route("api_token_list", Route::ok(fixture("api_token_list_ok.json")))
+        .route("api_token_revoke", Route::status(500, "Internal error".to_string()));
+    whm.accept_token("NEWTOKEN");
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+    let stored = |column: &str| rusqlite::Connection::open(cpcm.dbfile()).unwrap()
+        .query_row(&format!("SELECT {} FROM servers WHERE name = 'web01'", column), [], |r| r.get::<_, Option<String>>(0))
+        .unwrap();
+
+    // The new token goes in before the revoke fails, the old one is kept
+    // around to revoke next time
+    let out = cpcm.run(&["server", "rotate-token", "web01", "--old-token-name", "legacy"]);
+    assert!(!out.status.success());
+    assert_eq!(stored("apikey").as_deref(), Some("NEWTOKEN"));
+    assert_eq!(stored("stale_token_name").as_deref(), Some("legacy"));
+    assert!(stored("token_name").unwrap().starts_with("cpcm-"));
+
+    whm.set_route("api_token_revoke", Route::ok(fixture("api_token_revoke_ok.json")));
+    let out = cpcm.run(&["server", "rotate-token", "--server", "web01"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout(&out).contains("revoked legacy"));
+    assert_eq!(whm.requests("api_token_create").len(), 1);
+    assert!(whm.requests("api_token_revoke").last().unwrap().contains("token_name=legacy"));
+    assert_eq!(stored("stale_token_name"), None);
+}
+
+#[test]
+fn rotate_token_keeps_the_old_one_when_the_new_one_fails() {
+    let whm = MockWhm::start()
+        .route("myprivs", Route::ok(fixture("myprivs_all.json")))
+        .route("api_token_create", Route::ok(fixture("api_token_create_ok.json")))
+        .route("api_token_revoke", Route::ok(fixture("api_token_revoke_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+
+    let out = cpcm.run(&["server", "rotate-token", "web01"]);
+    assert!(!out.status.success());
+    assert!(stdout(&out).contains("doesn't work"));
+
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let (apikey, pending): (String, Option<String>) = db.query_row(
+        "SELECT apikey, pending_apikey FROM servers WHERE name = 'web01'", [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+    assert_eq!(apikey, APIKEY);
+    assert_eq!(pending, None);
+    assert!(whm.requests("api_token_revoke")[0].contains("token_name=cpcm-"));
+}
+
+#[test]
+fn rotate_token_cleans_up_after_an_interrupted_create() {
+    let whm = MockWhm::start()
+        .route("myprivs", Route::ok(fixture("myprivs_all.json")))
+        .route("api_token_create", Route::status(503, "Service unavailable".to_string()))
+        .route("api_token_revoke", Route::ok(fixture("api_token_revoke_ok.json")));
+    whm.accept_token("NEWTOKEN");
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+    let stored = |column: &str| rusqlite::Connection::open(cpcm.dbfile()).unwrap()
+        .query_row(&format!("SELECT {} FROM servers WHERE name = 'web01'", column), [], |r| r.get::<_, Option<String>>(0))
+        .unwrap();
+
+    // Never retried, and the name is on record even though no key came back
+    assert!(!cpcm.run(&["server", "rotate-token", "web01"]).status.success());
+    assert_eq!(whm.requests("api_token_create").len(), 1);
+    let orphan = stored("pending_token_name").expect("pending token name is recorded before create");
+    assert_eq!(stored("pending_apikey"), None);
+    assert_eq!(stored("apikey").as_deref(), Some(APIKEY));
+
+    // The server did make it, so the next run revokes it before starting over
+    let list = fixture("api_token_list_ok.json").replace("legacy", &orphan);
+    whm.set_route("api_token_list", Route::ok(list));
+    whm.set_route("api_token_create", Route::ok(fixture("api_token_create_ok.json")));
+    let out = cpcm.run(&["server", "rotate-token", "web01"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout(&out).contains(&format!("revoked unfinished {}", orphan)));
+    assert!(whm.requests("api_token_revoke")[0].contains(&format!("token_name={}", orphan)));
+    assert_eq!(stored("apikey").as_deref(), Some("NEWTOKEN"));
+    assert_eq!(stored("pending_token_name"), None);
+}
+
+#[test]
+fn collect_facts_and_find_servers_by_version() {
+    let old = MockWhm::start()
+        .route("version", Route::ok(fixture("version_110.json")))
+        .route("gethostname", Route::ok(fixture("gethostname_ok.json")))
+        .route("systemloadavg", Route::ok(fixture("systemloadavg_ok.json")))
+        .route("getdiskusage", Route::ok(fixture("getdiskusage_ok.json")))
+        .route("get_current_users_count", Route::ok(fixture("get_current_users_count_ok.json")))
+        .route("get_update_config", Route::ok(fixture("get_update_config_ok.json")))
+        .route("get_os_info", Route::ok(fixture("get_os_info_ok.json")))
+        .route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let new = MockWhm::start().route("version", Route::ok(fixture("version_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &old, &[]);
+    cpcm.add_server("web02", &new, &[]);
+
+    // Facts a server can't give are left empty, only the version is required
+    let out = cpcm.run(&["server", "facts", "--refresh"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let table = stdout(&out);
+    assert!(table.contains("11.110.0.17") && table.contains("11.120.0.5"));
+    assert!(table.contains("server1.example.com"));
+    assert!(table.contains("/backup 89%"));
+    assert!(table.contains("release") && table.contains("AlmaLinux 9.4"), "{}", table);
+
+    let csv = stdout(&cpcm.run(&["server", "facts", "--version", "110", "-o", "csv"]));
+    assert!(csv.contains("web01,11.110.0.17,release,AlmaLinux 9.4,server1.example.com,0.52,0.41,0.38,42,/backup,89.0"),
+        "{}", csv);
+    assert!(!csv.contains("web02"));
+    let newer = stdout(&cpcm.run(&["server", "facts", "--version", "120", "-o", "csv"]));
+    assert!(newer.contains("web02,11.120.0.5,,,"), "{}", newer);
+    let older = stdout(&cpcm.run(&["server", "facts", "--older-than", "11.120", "-o", "json"]));
+    assert!(older.contains("web01") && !older.contains("web02"));
+    assert!(stdout(&cpcm.run(&["server", "facts", "--version", "11.11"])).lines().all(|l| !l.contains("web")));
+
+    // Sync refreshes them when collect_facts is on
+    cpcm.set_config("collect_facts", serde_json::json!(true));
+    old.set_route("version", Route::ok(fixture("version_ok.json")));
+    assert!(cpcm.run(&["domain", "--sync", "--server", "web01"]).status.success());
+    assert!(stdout(&cpcm.run(&["server", "facts", "--version", "120"])).contains("web01"));
+
+    // Facts follow a rename and go with the server
+    assert!(cpcm.run(&["server", "edit", "web01", "--rename", "web03"]).status.success());
+    let json = stdout(&cpcm.run(&["server", "facts", "-o", "json"]));
+    assert!(json.contains("web03") && !json.contains("web01"));
+    assert!(cpcm.run(&["server", "remove", "web03", "-y"]).status.success());
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let count: i64 = db.query_row("SELECT COUNT(*) FROM server_facts", [], |r| r.get(0)).unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn tag_servers_and_select_by_tags() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    for name in ["web01", "web02", "web03"] {
+        cpcm.add_server(name, &whm, &[]);
+    }
+    assert!(cpcm.run(&["server", "tag", "add", "web01", "dc=fra", "role=shared"]).status.success());
+    assert!(cpcm.run(&["server", "tag", "add", "web02", "dc=fra", "role=reseller"]).status.success());
+    assert!(cpcm.run(&["server", "tag", "add", "web03", "dc=ams"]).status.success());
+    assert!(!cpcm.run(&["server", "tag", "add", "web03", "dc"]).status.success());
+    assert!(!cpcm.run(&["server", "tag", "add", "web03", "dc=a,b"]).status.success());
+
+    let list = |tag: &str| -> Vec<String> {
+        let out = cpcm.run(&["server", "list", "-o", "csv", "--tag", tag]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        stdout(&out).lines().skip(1).map(|l| l.split(',').next().unwrap().to_string()).collect()
+    };
+    assert_eq!(list("dc=fra,role=shared"), ["web01"]);
+    assert_eq!(list("dc=fra"), ["web01", "web02"]);
+    assert_eq!(list("role"), ["web01", "web02"]);
+    assert_eq!(list("role!=shared"), ["web02", "web03"]);
+    assert!(!cpcm.run(&["server", "list", "--tag", "dc=fra,,"]).status.success());
+
+    // Sync goes by the same selector
+    assert!(cpcm.run(&["domain", "--sync", "--tag", "dc=ams"]).status.success());
+    assert_eq!(whm.hits(), 1);
+
+    // Changing a value replaces it, removing by key=value only matches that value
+    assert!(cpcm.run(&["server", "tag", "add", "web01", "dc=ams"]).status.success());
+    assert_eq!(list("dc=ams"), ["web01", "web03"]);
+    assert!(stdout(&cpcm.run(&["server", "tag", "remove", "web01", "role=reseller"])).contains("Removed 0 tags"));
+    assert!(stdout(&cpcm.run(&["server", "tag", "remove", "web01", "role"])).contains("Removed 1 tags"));
+    assert_eq!(list("role"), ["web02"]);
+
+    // Tags follow a rename and go with the server
+    assert!(cpcm.run(&["server", "edit", "web02", "--rename", "web04"]).status.success());
+    assert!(stdout(&cpcm.run(&["server", "tag", "list", "--server", "web04"])).contains("dc=fra,role=reseller"));
+    assert!(cpcm.run(&["server", "remove", "web04", "-y"]).status.success());
+    let db = rusqlite::Connection::open(cpcm.dbfile()).unwrap();
+    let count: i64 = db.query_row("SELECT COUNT(*) FROM server_tags WHERE server_name = 'web04'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn pinned_certificate_has_to_match() {
+    let whm = MockWhm::start_tls().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let add = |tls: &[&str]| {
+        let mut args = vec!["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+            "--port", &port, "--no-test"];
+        args.extend_from_slice(tls);
+        assert!(cpcm.run_with_stdin(&args, &format!("{}\n", APIKEY)).status.success());
+    };
+
+    add(&["--tls-fingerprint", &"00".repeat(32)]);
+    let out = cpcm.run(&["server", "test", "web01"]);
+    assert!(!out.status.success());
+    assert!(stdout(&out).contains("tls error"), "{}", stdout(&out));
+    assert!(stdout(&out).contains("does not match pinned"), "{}", stdout(&out));
+    assert_eq!(whm.hits(), 0);
+
+    add(&["--tls-fingerprint", &server_fingerprint()]);
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(whm.hits(), 1);
+}
+
+#[test]
+fn ca_bundle_replaces_the_system_store() {
+    let whm = MockWhm::start_tls().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let ca = tls_fixture("ca.pem");
+    let add = |tls: &[&str]| {
+        let mut args = vec!["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+            "--port", &port, "--no-test"];
+        args.extend_from_slice(tls);
+        assert!(cpcm.run_with_stdin(&args, &format!("{}\n", APIKEY)).status.success());
+    };
+
+    // The test CA isn't in the system store
+    add(&[]);
+    assert!(!cpcm.run(&["domain", "--sync"]).status.success());
+    assert_eq!(whm.hits(), 0);
+
+    add(&["--tls-ca", ca.to_str().unwrap()]);
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(whm.hits(), 1);
+}
+
+#[test]
+fn trust_pins_the_presented_certificate() {
+    let whm = MockWhm::start_tls().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let proxy = ConnectProxy::start_tls();
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let proxy_url = format!("https://127.0.0.1:{}", proxy.port);
+    for (name, extra) in [("web01", vec![]), ("web02", vec!["--proxy", &proxy_url])] {
+        let mut args = vec!["server", "add", "--name", name, "--ip", "127.0.0.1", "--user", "root",
+            "--port", &port, "--no-test"];
+        args.extend_from_slice(&extra);
+        assert!(cpcm.run_with_stdin(&args, &format!("{}\n", APIKEY)).status.success());
+    }
+    let pinned = |name: &str| rusqlite::Connection::open(cpcm.dbfile()).unwrap()
+        .query_row("SELECT tls_fingerprint FROM servers WHERE name = ?1", [name], |r| r.get::<_, Option<String>>(0))
+        .unwrap();
+
+    assert!(!cpcm.run(&["server", "trust", "web01"]).status.success());
+    assert_eq!(pinned("web01"), None);
+    let out = cpcm.run(&["server", "trust", "web01", "--yes"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(pinned("web01"), Some(server_fingerprint()));
+    assert!(stdout(&out).contains(&server_fingerprint()[..2].to_uppercase()));
+    assert!(cpcm.run(&["domain", "--sync", "--server", "web01"]).status.success());
+
+    // An https:// proxy is checked like any other TLS peer, against the
+    // system store, which SSL_CERT_FILE stands in for here
+    let out = cpcm.run(&["server", "trust", "web02", "--yes"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("TLS to proxy"), "{}", String::from_utf8_lossy(&out.stderr));
+    let ca = tls_fixture("ca.pem");
+    let out = cpcm.run_with_env(&["server", "trust", "web02", "--yes"], "", &[("SSL_CERT_FILE", ca.to_str().unwrap())]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(pinned("web02"), Some(server_fingerprint()));
+    assert_eq!(proxy.targets().last(), Some(&format!("127.0.0.1:{}", port)));
+}
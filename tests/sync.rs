@@ -0,0 +1,368 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{fixture, stdout, Cpcm, MockWhm, Route, Socks5Proxy, APIKEY};
+
+#[test]
+fn sync_then_find_domains() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let summary = stdout(&out);
+    assert!(summary.contains("web01"));
+    assert!(summary.contains("ok"));
+
+    let found = stdout(&cpcm.run(&["domain", "--name", "alpha"]));
+    assert!(found.contains("alpha.example.com"));
+    assert!(found.contains("shop.alpha.example.com"));
+    assert!(!found.contains("bravo.example.org"));
+}
+
+#[test]
+fn malformed_rows_are_skipped() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_malformed.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let found = stdout(&cpcm.run(&["domain", "--name", "example.net"]));
+    assert!(found.contains("charlie.example.net"));
+    assert!(!found.contains("wrongtype.example.net"));
+}
+
+#[test]
+fn auth_failures_are_reported() {
+    let denied = MockWhm::start().route("get_domain_info", Route::ok(fixture("access_denied.json")));
+    let badkey = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("denied", &denied, &[]);
+    let port = badkey.port.to_string();
+    cpcm.run_with_stdin(&["server", "add", "--name", "badkey", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port], "WRONGTOKEN\n");
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    let summary = stdout(&out);
+    assert_eq!(summary.matches("auth failure").count(), 2, "{}", summary);
+}
+
+#[test]
+fn unusable_server_settings_are_reported_as_config_errors() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    // A control character can't go in the Authorization header
+    let out = cpcm.run_with_env(&["server", "add", "--name", "badkey", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port, "--apikey-env", "CPCM_TEST_KEY", "--no-test"], "",
+        &[("CPCM_TEST_KEY", "BAD\u{1}KEY")]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    let summary = stdout(&out);
+    assert!(summary.lines().any(|l| l.contains("badkey") && l.contains("config error")), "{}", summary);
+    assert!(!summary.contains("bad json"), "{}", summary);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Server is misconfigured: Invalid API key"));
+    assert_eq!(whm.hits(), 0);
+}
+
+#[test]
+fn slow_server_times_out_without_holding_up_others() {
+    let slow = MockWhm::start()
+        .route("get_domain_info", Route::slow(fixture("get_domain_info_ok.json"), Duration::from_secs(5)));
+    let fast = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_malformed.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("slow", &slow, &["--read-timeout-secs", "1", "--max-retries", "0"]);
+    cpcm.add_server("fast", &fast, &[]);
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    let summary = stdout(&out);
+    assert!(summary.lines().any(|l| l.contains("slow") && l.contains("timeout")), "{}", summary);
+    assert!(summary.lines().any(|l| l.contains("fast") && l.contains("ok")), "{}", summary);
+
+    let found = stdout(&cpcm.run(&["domain", "--name", "charlie"]));
+    assert!(found.contains("charlie.example.net"));
+}
+
+#[test]
+fn server_errors_are_retried() {
+    let whm = MockWhm::start().route("get_domain_info", Route::status(503, String::new()));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &["--max-retries", "2", "--retry-backoff-ms", "10"]);
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    assert_eq!(whm.hits(), 3);
+}
+
+#[test]
+fn refused_connections_are_retried_whatever_the_url_says() {
+    // Nothing listens here once the listener is dropped
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let cpcm = Cpcm::init();
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port, "--base-path", "/ssl-certificate-handshake",
+        "--max-retries", "2", "--retry-backoff-ms", "300", "--no-test"], &format!("{}\n", APIKEY));
+    assert!(out.status.success());
+
+    // Two backoffs of 300ms and 600ms, which a TLS failure wouldn't wait for
+    let started = std::time::Instant::now();
+    assert!(!cpcm.run(&["domain", "--sync"]).status.success());
+    assert!(started.elapsed() >= Duration::from_millis(900), "not retried, took {:?}", started.elapsed());
+}
+
+#[test]
+fn out_of_range_retry_and_rate_settings_are_refused() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let add = |extra: &[&str]| {
+        let mut args = vec!["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+            "--scheme", "http", "--port", &port, "--no-test"];
+        args.extend_from_slice(extra);
+        cpcm.run_with_stdin(&args, &format!("{}\n", APIKEY))
+    };
+    for bad in [["--requests-per-second", "1e-300"], ["--requests-per-second", "inf"],
+        ["--requests-per-second", "NaN"], ["--max-retries", "1000"]] {
+        assert!(!add(&bad).status.success(), "{:?} was accepted", bad);
+    }
+    assert!(add(&["--requests-per-second", "0", "--max-retries", "10"]).status.success());
+
+    cpcm.set_config("requests_per_second", serde_json::json!(1e-300));
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("requests_per_second"));
+    cpcm.set_config("requests_per_second", serde_json::json!(5.0));
+    cpcm.set_config("max_retries", serde_json::json!(40));
+    assert!(!cpcm.run(&["domain", "--sync"]).status.success());
+    cpcm.set_config("max_retries", serde_json::json!(2));
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+}
+
+#[test]
+fn failed_server_keeps_its_domains() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &["--max-retries", "0"]);
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    whm.set_route("get_domain_info", Route::status(500, String::new()));
+    assert!(!cpcm.run(&["domain", "--sync"]).status.success());
+
+    let found = stdout(&cpcm.run(&["domain", "--name", "example"]));
+    assert!(found.contains("alpha.example.com"));
+    assert!(found.contains("bravo.example.org"));
+}
+
+#[test]
+fn resync_updates_and_removes_domains() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("web01", &whm, &[]);
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    let changed = fixture("get_domain_info_ok.json")
+        .replace("ea-php74", "ea-php83")
+        .replace("bravo.example.org", "delta.example.org");
+    whm.set_route("get_domain_info", Route::ok(changed));
+
+    let dry = stdout(&cpcm.run(&["domain", "--sync", "--dry-run"]));
+    assert!(dry.contains("+ delta.example.org"), "{}", dry);
+    assert!(dry.contains("- bravo.example.org"), "{}", dry);
+    assert!(dry.contains("php_version: ea-php74 -> ea-php83"), "{}", dry);
+    assert!(stdout(&cpcm.run(&["domain", "--name", "bravo"])).contains("bravo.example.org"));
+
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+    let found = stdout(&cpcm.run(&["domain", "--name", "example"]));
+    assert!(found.contains("ea-php83"));
+    assert!(found.contains("delta.example.org"));
+    assert!(!found.contains("bravo.example.org"));
+
+    let history = stdout(&cpcm.run(&["domain", "history", "shop.alpha.example.com"]));
+    assert!(history.contains("php_version: ea-php74 -> ea-php83"), "{}", history);
+}
+
+#[test]
+fn dry_run_leaves_no_trace() {
+    let whm = MockWhm::start()
+        .route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")))
+        .route("version", Route::ok(fixture("version_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.set_config("cache_responses", true.into());
+    cpcm.set_config("collect_facts", true.into());
+    cpcm.add_server("web01", &whm, &[]);
+
+    let out = cpcm.run(&["domain", "--sync", "--dry-run"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!cpcm.datadir.path().join("responses").exists());
+    assert!(whm.requests("version").is_empty());
+    assert_eq!(whm.hits(), 1);
+    assert!(!stdout(&cpcm.run(&["server", "facts"])).contains("web01"));
+}
+
+#[test]
+fn import_saved_response() {
+    let whm = MockWhm::start();
+    let cpcm = Cpcm::init();
+    cpcm.add_server("offline", &whm, &[]);
+
+    let saved = cpcm.datadir.path().join("saved.json");
+    std::fs::write(&saved, fixture("get_domain_info_ok.json")).unwrap();
+    let out = cpcm.run(&["domain", "import", "--server", "offline", saved.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(whm.hits(), 0);
+
+    let found = stdout(&cpcm.run(&["domain", "--name", "bravo"]));
+    assert!(found.contains("bravo.example.org"));
+
+    let out = cpcm.run(&["domain", "import", "--server", "nosuchserver", saved.to_str().unwrap()]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn unresolvable_hostname_falls_back_to_the_ip() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    // .invalid never resolves
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "web01", "--ip", "127.0.0.1", "--user", "root",
+        "--hostname", "web01.invalid", "--use-hostname", "--scheme", "http", "--port", &port, "--max-retries", "0",
+        "--no-test"], &format!("{}\n", APIKEY));
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    // Reached on the IP, still addressed by name
+    assert_eq!(whm.hosts(), vec![format!("web01.invalid:{}", port)]);
+    assert!(stdout(&cpcm.run(&["domain", "--name", "bravo"])).contains("bravo.example.org"));
+}
+
+#[test]
+fn sync_through_http_proxy() {
+    // The mock doubles as the proxy: plain http requests go to it in absolute
+    // form and the server's own address is never dialled.
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    let proxy = format!("http://127.0.0.1:{}", whm.port);
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "proxied", "--ip", "10.255.255.1", "--user", "root",
+        "--scheme", "http", "--connect-timeout-secs", "2", "--proxy", &proxy, "--no-test"], "GOODTOKEN\n");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(whm.hits(), 1);
+    assert!(stdout(&cpcm.run(&["domain", "--name", "bravo"])).contains("bravo.example.org"));
+}
+
+#[test]
+fn sync_through_socks5_proxy() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let socks = Socks5Proxy::start();
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let proxy = format!("socks5h://127.0.0.1:{}", socks.port);
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "socks", "--ip", "127.0.0.1", "--user", "root",
+        "--hostname", "localhost", "--use-hostname", "--scheme", "http", "--port", &port, "--proxy", &proxy,
+        "--no-test"], &format!("{}\n", APIKEY));
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    // socks5h leaves the name for the proxy to resolve
+    assert_eq!(socks.targets(), vec![format!("localhost:{}", port)]);
+    assert_eq!(whm.hits(), 1);
+    assert!(stdout(&cpcm.run(&["domain", "--name", "bravo"])).contains("bravo.example.org"));
+}
+
+#[test]
+fn failed_ssh_tunnel_is_reported() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    // Nothing listens here once the listener is dropped, so ssh gives up
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cpcm = Cpcm::init();
+    let port = whm.port.to_string();
+    let via = format!("-p {} nobody@127.0.0.1", closed);
+    let out = cpcm.run_with_stdin(&["server", "add", "--name", "tunneled", "--ip", "127.0.0.1", "--user", "root",
+        "--scheme", "http", "--port", &port, "--ssh-via", &via, "--connect-timeout-secs", "5", "--no-test"],
+        &format!("{}\n", APIKEY));
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = cpcm.run(&["domain", "--sync"]);
+    assert!(!out.status.success());
+    let report = format!("{}{}", stdout(&out), String::from_utf8_lossy(&out.stderr));
+    assert!(report.contains(&format!("ssh to {} exited", via)), "{}", report);
+    assert!(report.contains("before the tunnel came up"), "{}", report);
+    assert_eq!(whm.hits(), 0);
+}
+
+#[test]
+fn cached_responses_replay_offline() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_malformed.json")));
+    let cpcm = Cpcm::init();
+    cpcm.set_config("cache_responses", true.into());
+    cpcm.set_config("cache_keep", 2.into());
+    cpcm.add_server("web01", &whm, &[]);
+    for _ in 0..3 {
+        assert!(cpcm.run(&["domain", "--sync"]).status.success());
+    }
+
+    let cached = cpcm.datadir.path().join("responses/web01/get_domain_info");
+    assert_eq!(std::fs::read_dir(&cached).unwrap().count(), 2);
+
+    let hits = whm.hits();
+    let out = cpcm.run(&["debug", "replay", "web01"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let replay = stdout(&out);
+    assert!(replay.contains("1 rows usable, 2 rejected"), "{}", replay);
+    assert!(replay.contains("wrongtype.example.net"), "{}", replay);
+    assert!(replay.contains("Domain not provided"), "{}", replay);
+    assert_eq!(whm.hits(), hits);
+
+    assert!(!cpcm.run(&["debug", "replay", "nosuchserver"]).status.success());
+}
+
+#[test]
+fn filter_domains_with_an_expression() {
+    let whm = MockWhm::start().route("get_domain_info", Route::ok(fixture("get_domain_info_ok.json")));
+    let cpcm = Cpcm::init();
+    cpcm.add_server("fra01", &whm, &[]);
+    assert!(cpcm.run(&["domain", "--sync"]).status.success());
+
+    let find = |filter: &str| {
+        let out = cpcm.run(&["domain", "--filter", filter]);
+        assert!(out.status.success(), "{}: {}", filter, String::from_utf8_lossy(&out.stderr));
+        stdout(&out)
+    };
+    let found = find("php_version=ea-php74 and modsecurity_enabled=0 and server~fra*");
+    assert!(found.contains("shop.alpha.example.com"));
+    assert!(!found.contains("bravo.example.org"));
+
+    let found = find("domain~*.org or php_version = 'ea-php81'");
+    assert!(found.contains("bravo.example.org") && found.contains("alpha.example.com"));
+    assert!(!found.contains("shop.alpha"));
+    assert!(!find("server!~fra* OR modsecurity_enabled>1").contains("example"));
+    assert!(find("port>=80 and domain!=alpha.example.com").contains("shop.alpha.example.com"));
+
+    // Values are bound, never pasted into the SQL
+    assert!(!find("domain~*'--").contains("example"));
+    let found = stdout(&cpcm.run(&["domain", "--name", "x' OR '1'='1"]));
+    assert!(!found.contains("example"), "{}", found);
+    assert!(!find("domain~*_*").contains("example"));
+
+    let out = cpcm.run(&["domain", "--filter", "colour=red"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown column colour"));
+    for bad in ["domain", "domain=", "port>eighty", "domain=a nor user=b", "domain='open"] {
+        assert!(!cpcm.run(&["domain", "--filter", bad]).status.success(), "{}", bad);
+    }
+
+    // --name and --filter together have to both match
+    let found = stdout(&cpcm.run(&["domain", "--name", "alpha", "--filter", "domain_type=main"]));
+    assert!(found.contains("alpha.example.com") && !found.contains("shop.alpha"));
+}